@@ -0,0 +1,88 @@
+use std::fs;
+
+use nix::errno::Errno;
+
+use crate::error::{Error, Result};
+
+/// Linux capability names, indexed by capability number
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Mask with every known capability set
+pub const ALL: u64 = (1 << CAPABILITIES.len()) - 1;
+
+/// Parse a capability name into its bit in a capability mask
+///
+/// Names are case insensitive and the `CAP_` prefix is optional.
+pub fn parse(name: &str) -> Option<u64> {
+    let name = name.to_ascii_uppercase();
+    let name = if name.starts_with("CAP_") {
+        name
+    } else {
+        format!("CAP_{}", name)
+    };
+    CAPABILITIES
+        .iter()
+        .position(|cap| *cap == name)
+        .map(|i| 1 << i)
+}
+
+/// Effective capability set of the current process
+pub fn effective() -> Result<u64> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .ok_or(Error::Sys(Errno::EINVAL))
+}
+
+/// Fail with `Error::MissingCapability` unless `name` is in the effective set
+pub fn require(name: &'static str) -> Result<()> {
+    let bit = parse(name).ok_or(Error::Sys(Errno::EINVAL))?;
+    if effective()? & bit == 0 {
+        return Err(Error::MissingCapability(name));
+    }
+    Ok(())
+}
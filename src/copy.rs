@@ -0,0 +1,43 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use nix::unistd::Pid;
+
+use crate::error::Result;
+use crate::systemd::enter_mount_ns;
+
+/// Copy file `host` into the mount namespace of `init` as `inner`
+pub fn copy_in(init: Pid, host: &str, inner: &str) -> Result<()> {
+    let mut src = File::open(host)?;
+    let mode = src.metadata()?.permissions().mode();
+    enter_mount_ns(init)?;
+    let mut dst = create(inner, mode)?;
+    io::copy(&mut src, &mut dst)?;
+    Ok(())
+}
+
+/// Copy file `inner` out of the mount namespace of `init` as `host`
+pub fn copy_out(init: Pid, inner: &str, host: &str) -> Result<()> {
+    // Host side must be opened before leaving the host mount namespace
+    let mut dst = create(host, 0o600)?;
+    enter_mount_ns(init)?;
+    let mut src = File::open(inner)?;
+    let mode = src.metadata()?.permissions().mode();
+    io::copy(&mut src, &mut dst)?;
+    dst.set_permissions(fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// Create or truncate `path` with exactly permission `mode`
+fn create(path: &str, mode: u32) -> Result<File> {
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    // Mode on open is filtered by umask
+    f.set_permissions(fs::Permissions::from_mode(mode))?;
+    Ok(f)
+}
@@ -0,0 +1,107 @@
+use std::fs::{self, Permissions};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+use nix::cmsg_space;
+use nix::errno::Errno;
+use nix::sys::epoll::{self, EpollEvent, EpollFlags, EpollOp};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use nix::unistd::close;
+
+use crate::error::{Error, Result};
+use crate::registry;
+
+const HANGUP_EVENT: u64 = 1;
+
+const ATTACH_EVENT: u64 = 2;
+
+/// Socket the holder of detached `unit` hands its pty master out on
+fn socket_path(unit: &str) -> PathBuf {
+    registry::dir().join(format!("{}.sock", unit))
+}
+
+/// Hold pty `master` of detached `unit` until its shell hangs up
+///
+/// Meanwhile every `attach` to `unit` gets a duplicate of `master`.
+pub fn hold(master: RawFd, unit: &str) -> Result<()> {
+    let path = socket_path(unit);
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let ret = fs::set_permissions(&path, Permissions::from_mode(0o600))
+        .map_err(Error::from)
+        .and_then(|_| serve(master, &listener));
+    let _ = fs::remove_file(&path);
+    ret
+}
+
+fn serve(master: RawFd, listener: &UnixListener) -> Result<()> {
+    let epoll = epoll::epoll_create()?;
+    let ret = (|| {
+        // No events requested on master, hangups are always reported
+        let mut hangup = EpollEvent::new(EpollFlags::empty(), HANGUP_EVENT);
+        let mut attach = EpollEvent::new(EpollFlags::EPOLLIN, ATTACH_EVENT);
+        epoll::epoll_ctl(epoll, EpollOp::EpollCtlAdd, master, &mut hangup)?;
+        let fd = listener.as_raw_fd();
+        epoll::epoll_ctl(epoll, EpollOp::EpollCtlAdd, fd, &mut attach)?;
+
+        let mut events = [EpollEvent::empty(); 2];
+        loop {
+            let n = match epoll::epoll_wait(epoll, &mut events, -1) {
+                Ok(n) => n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e),
+            };
+            for ev in &events[..n] {
+                match ev.data() {
+                    HANGUP_EVENT => return Ok(()),
+                    ATTACH_EVENT => {
+                        // A failing client must not end the session
+                        if let Ok((stream, _)) = listener.accept() {
+                            let _ = send_fd(&stream, master);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    })();
+    close(epoll)?;
+    Ok(ret?)
+}
+
+fn send_fd(stream: &UnixStream, fd: RawFd) -> Result<()> {
+    let fds = [fd];
+    let iov = [IoSlice::new(b"\0")];
+    let cmsg = [ControlMessage::ScmRights(&fds)];
+    sendmsg::<()>(stream.as_raw_fd(), &iov, &cmsg, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+/// Get the pty master of detached `unit` from its holder
+///
+/// # Errors
+///
+/// `ENOENT` if `unit` is not detached.
+pub fn attach(unit: &str) -> Result<RawFd> {
+    let stream = UnixStream::connect(socket_path(unit))?;
+    let mut buf = [0; 1];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut space = cmsg_space!(RawFd);
+    let msg = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut space),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(&fd) = fds.first() {
+                return Ok(fd);
+            }
+        }
+    }
+    Err(Errno::EPROTO.into())
+}
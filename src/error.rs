@@ -0,0 +1,73 @@
+use std::fmt;
+use std::io;
+
+use nix::errno::Errno;
+
+/// Error of angea
+#[derive(Debug)]
+pub enum Error {
+    /// A system call or sd-bus function failed
+    Sys(Errno),
+    /// An I/O operation of the standard library failed
+    Io(io::Error),
+    /// A D-Bus call was answered with error `name`, like `org.freedesktop.systemd1.NoSuchUnit`
+    Dbus { name: String, message: String },
+    /// Waiting for what is named took too long
+    Timeout(&'static str),
+    /// systemd is not running
+    NotRunning,
+    /// The named capability is missing from the effective set
+    MissingCapability(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Closest errno of the error
+    pub fn errno(&self) -> Errno {
+        match self {
+            Error::Sys(e) => *e,
+            Error::Io(e) => Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO)),
+            Error::Dbus { .. } => Errno::EIO,
+            Error::Timeout(_) => Errno::ETIMEDOUT,
+            Error::NotRunning => Errno::ESRCH,
+            Error::MissingCapability(_) => Errno::EPERM,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sys(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Dbus { name, message } if message.is_empty() => write!(f, "{}", name),
+            Error::Dbus { name, message } => write!(f, "{}: {}", name, message),
+            Error::Timeout(what) => write!(f, "timed out waiting for {}", what),
+            Error::NotRunning => write!(f, "systemd is not running"),
+            Error::MissingCapability(cap) => write!(f, "{} is required, run angea as root", cap),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Sys(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(e: Errno) -> Self {
+        Error::Sys(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use nix::unistd::{close, dup, Pid};
+
+use crate::bus::Bus;
+use crate::error::Result;
+use crate::shell::{self, get_pty, Session, ShellOptions};
+
+/// Shell for library users driving its I/O themselves instead of by `PTYForward`
+///
+/// Dropping it stops the shell unit, `finish` lets the shell end and collects its exit status.
+pub struct ShellHandle {
+    bus: Bus,
+    init: Pid,
+    session: Session,
+    finished: bool,
+}
+
+/// Boot systemd unless running, and start a shell with `opts` on a new pty
+///
+/// Boot options come from the environment as for `angea shell`.
+pub fn open_shell(opts: &ShellOptions) -> Result<ShellHandle> {
+    let (outcome, bus) = crate::boot_ready(&crate::start_options_from_env()?, false)?;
+    let session = get_pty(&bus, opts)?;
+    Ok(ShellHandle {
+        bus,
+        init: outcome.pid(),
+        session,
+        finished: false,
+    })
+}
+
+impl ShellHandle {
+    /// Non-blocking pty master, read the output of the shell from it and write its input to it
+    ///
+    /// Reads fail with `EIO` once the shell hung up.
+    pub fn master(&self) -> RawFd {
+        self.session.master()
+    }
+
+    /// Duplicate of `master` as a `File`, still non-blocking
+    pub fn file(&self) -> Result<File> {
+        let fd = dup(self.session.master())?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Pid of systemd, exiting ends the session as well
+    pub fn init(&self) -> Pid {
+        self.init
+    }
+
+    /// Unit and pty of the shell
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Close master, hanging the shell up unless it exited, and return its exit status
+    pub fn finish(mut self) -> Result<i32> {
+        self.finished = true;
+        close(self.session.master())?;
+        shell::exit_status(
+            &self.bus,
+            self.session.unit(),
+            Some(shell::EXIT_TIMEOUT),
+            None,
+        )
+    }
+}
+
+impl Drop for ShellHandle {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.bus.stop_unit(self.session.unit());
+            let _ = close(self.session.master());
+        }
+    }
+}
@@ -1,44 +1,925 @@
+mod bus;
+
+mod capability;
+
+mod copy;
+
+mod detach;
+
+mod error;
+
+mod handle;
+
+mod ptyfwd;
+
+mod record;
+
+mod registry;
+
 mod shell;
 
 mod systemd;
 
-use nix::Result;
-use shell::{get_pty, PTYForward};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::signal::Signal;
+use nix::sys::signalfd::{SfdFlags, SigSet, SignalFd};
+use nix::sys::stat::Mode;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use nix::unistd::{close, dup2, fork, setsid, ForkResult};
+use registry::{Record, Registration};
+use shell::{is_documentation_uri, is_user_or_id};
 use std::env;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::RawFd;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use systemd::{ShutdownMethod, ShutdownOutcome, ShutdownTimeouts, HARDENED_MASK_PATHS};
+
+pub use bus::Bus;
+pub use error::{Error, Result};
+pub use handle::{open_shell, ShellHandle};
+pub use ptyfwd::{PTYForward, PTYForwardBuilder, SessionEnd};
+pub use shell::{
+    environment_from_env, get_pty, list_shells, PropertyValue, Session, ShellOptions, ShellUnit,
+};
+pub use systemd::StartOptions;
+
+/// How long to wait for systemd to answer on the bus after boot
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub fn cmd() {
+/// How often `--exit-on-idle` checks for active sessions
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What `boot` found or did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootOutcome {
+    /// A systemd was already running with this pid
+    AlreadyRunning(Pid),
+    /// A new systemd was started with this pid
+    Started(Pid),
+}
+
+impl BootOutcome {
+    /// Pid of the running systemd
+    pub fn pid(&self) -> Pid {
+        match *self {
+            BootOutcome::AlreadyRunning(pid) | BootOutcome::Started(pid) => pid,
+        }
+    }
+}
+
+/// Run the angea command line, printing the error if it fails
+pub fn cmd() -> Result<()> {
     let mut args = env::args();
     args.next();
     let ret = match args.next() {
-        Some(s) if s == "boot" => boot(),
-        Some(s) if s == "shutdown" => shutdown(),
-        Some(s) if s == "shell" => shell(args.next()),
+        Some(s) if s == "boot" => boot_cmd(args),
+        Some(s) if s == "shutdown" => shutdown(args),
+        Some(s) if s == "status" => status(),
+        Some(s) if s == "probe" => probe(),
+        Some(s) if s == "prune" => prune(),
+        Some(s) if s == "list" => list(),
+        Some(s) if s == "stop" => stop(args.next()),
+        Some(s) if s == "reset" => reset(),
+        Some(s) if s == "cp" => cp(args.next(), args.next()),
+        Some(s) if s == "shell" => shell_cmd(args),
+        Some(s) if s == "exec" => exec_cmd(args),
+        Some(s) if s == "attach" => attach(args.next()),
         _ => help(),
     };
-    if let Err(e) = ret {
+    if let Err(e) = &ret {
         eprintln!("{}", e);
     }
+    ret
+}
+
+/// Open recording `path` readable by the owner only, appending to it or truncating it
+fn open_recording(path: &str, append: bool) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| {
+            eprintln!("failed to open recording {}: {}", path, e);
+            Error::from(e)
+        })
+}
+
+/// Parsed `shell` command line
+struct ShellArgs {
+    opts: ShellOptions,
+    forward: PTYForwardBuilder,
+    bus_fd: Option<RawFd>,
+    recover: bool,
+    /// Enter the mount namespace of systemd before opening the pty
+    setns: bool,
+}
+
+fn shell_cmd(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut opts = ShellOptions::from_env()?;
+    let mut forward = PTYForward::builder();
+    let mut bus_fd = env::var("ANGEA_BUS_FD").ok();
+    let mut typescript = env::var("ANGEA_TYPESCRIPT").ok();
+    let mut asciicast = env::var("ANGEA_ASCIICAST").ok();
+    let mut recover = false;
+    let mut setns = true;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--documentation" => {
+                let uri = flag_value(&arg, args.next())?;
+                if !is_documentation_uri(&uri) {
+                    eprintln!("invalid documentation uri: {}", uri);
+                    return Err(Errno::EINVAL.into());
+                }
+                opts.documentation.push(uri);
+            }
+            "--cap-add" => {
+                let cap = parse_capability(&flag_value(&arg, args.next())?)?;
+                opts.ambient_capabilities |= cap;
+            }
+            "--cap-drop" => {
+                let cap = parse_capability(&flag_value(&arg, args.next())?)?;
+                let set = opts.capability_bounding_set.unwrap_or(capability::ALL);
+                opts.capability_bounding_set = Some(set & !cap);
+            }
+            "--resize-interval" => {
+                let value = flag_value(&arg, args.next())?;
+                let ms = value.parse().map_err(|_| {
+                    eprintln!("invalid interval: {}", value);
+                    Errno::EINVAL
+                })?;
+                forward = forward.resize_interval(Duration::from_millis(ms));
+            }
+            "--idle-timeout" => {
+                forward = forward.idle_timeout(parse_duration(&flag_value(&arg, args.next())?)?);
+            }
+            "--environment-file" => {
+                let value = flag_value(&arg, args.next())?;
+                // `-` ignores a missing file, as in EnvironmentFile=
+                let (path, ignore_missing) = match value.strip_prefix('-') {
+                    Some(path) => (path.to_string(), true),
+                    None => (value, false),
+                };
+                if !path.starts_with('/') {
+                    eprintln!("environment file must be absolute: {}", path);
+                    return Err(Errno::EINVAL.into());
+                }
+                opts.environment_files.push((path, ignore_missing));
+            }
+            "--working-directory" => {
+                let dir = flag_value(&arg, args.next())?;
+                if !dir.starts_with('/') && !dir.starts_with('~') {
+                    eprintln!(
+                        "working directory must be absolute or start with ~: {}",
+                        dir
+                    );
+                    return Err(Errno::EINVAL.into());
+                }
+                opts.working_directory = Some(dir);
+            }
+            "--bus-fd" => bus_fd = Some(flag_value(&arg, args.next())?),
+            "--start-timeout" => {
+                opts.start_timeout = Some(parse_duration(&flag_value(&arg, args.next())?)?);
+            }
+            "--memory-max" => opts.memory_max = Some(parse_bytes(&flag_value(&arg, args.next())?)?),
+            "--group" => opts.group = Some(parse_user(&flag_value(&arg, args.next())?)?),
+            "--supplementary-group" => {
+                let group = parse_user(&flag_value(&arg, args.next())?)?;
+                opts.supplementary_groups.push(group);
+            }
+            "--no-tty-reset" => opts.keep_tty_state = true,
+            "--pam-name" => opts.pam_name = Some(flag_value(&arg, args.next())?),
+            "--slice" => {
+                let slice = flag_value(&arg, args.next())?;
+                if !slice.ends_with(".slice") {
+                    eprintln!("slice name must end with .slice: {}", slice);
+                    return Err(Errno::EINVAL.into());
+                }
+                opts.slice = Some(slice);
+            }
+            "--cpu-quota" => {
+                let value = flag_value(&arg, args.next())?;
+                let percent = value
+                    .strip_suffix('%')
+                    .unwrap_or(&value)
+                    .parse()
+                    .map_err(|_| {
+                        eprintln!("invalid cpu quota: {}", value);
+                        Errno::EINVAL
+                    })?;
+                opts.cpu_quota = Some(percent);
+            }
+            "--recover" => recover = true,
+            "--no-detach" => forward = forward.escape(None),
+            "--no-setns" => setns = false,
+            "--typescript" => typescript = Some(flag_value(&arg, args.next())?),
+            "--asciicast" => asciicast = Some(flag_value(&arg, args.next())?),
+            "--" => {
+                opts.args.extend(args.by_ref());
+                break;
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option: {}", arg);
+                return Err(Errno::EINVAL.into());
+            }
+            _ => opts.user = Some(parse_user(&arg)?),
+        }
+    }
+    if let Some(path) = typescript {
+        forward = forward.typescript(open_recording(&path, true)?);
+    }
+    // A cast holds one session, and takes precedence over a typescript
+    if let Some(path) = asciicast {
+        forward = forward.asciicast(open_recording(&path, false)?);
+    }
+    let status = shell(ShellArgs {
+        opts,
+        forward,
+        bus_fd: parse_bus_fd(bus_fd)?,
+        recover,
+        setns,
+    })?;
+    process::exit(status);
+}
+
+/// Run a shell session, returning the exit status of the shell
+fn shell(args: ShellArgs) -> Result<i32> {
+    let (init, bus) = connect(args.bus_fd, args.recover)?;
+    // The bus stays connected, while the pty and the registry are now those systemd sees
+    let entered = args.setns && enter_systemd(init);
+
+    let user = args.opts.user_or_root().to_string();
+    let session = get_pty(&bus, &args.opts)?;
+    let registration = register(&session, user.clone());
+    let end = forward(args.forward, session.master(), init).inspect_err(|_| {
+        // Do not leave the unit of a broken session behind
+        let _ = bus.stop_unit(session.unit());
+    })?;
+    match end {
+        SessionEnd::Hangup => {
+            shell::exit_status(&bus, session.unit(), Some(shell::EXIT_TIMEOUT), None)
+        }
+        SessionEnd::InitExited => {
+            eprintln!("systemd exited, session closed");
+            Ok(1)
+        }
+        SessionEnd::Detached | SessionEnd::Idle => {
+            if end == SessionEnd::Idle {
+                eprintln!("session was idle for too long");
+            }
+            // The holder registers the session again under its own pid
+            drop(registration);
+            keep_detached(&session, user, || {
+                if entered {
+                    Bus::private()
+                } else {
+                    Bus::namespace(init)
+                }
+            })?;
+            Ok(0)
+        }
+        SessionEnd::Signaled(sig) => {
+            // Let systemd stop the shell as gracefully as configured
+            bus.stop_unit(session.unit())?;
+            Ok(128 + sig as i32)
+        }
+    }
+}
+
+/// Record `session` in the registry, warning if that fails
+fn register(session: &Session, user: String) -> Option<Registration> {
+    register_unit(session.unit(), session.slave_path(), user)
+}
+
+/// Record `unit` on pty `pts` in the registry, warning if that fails
+fn register_unit(unit: &str, pts: &str, user: String) -> Option<Registration> {
+    Record::new(unit.to_string(), pts.to_string(), user)
+        .register()
+        .inspect_err(|e| eprintln!("failed to register session: {}", e))
+        .ok()
+}
+
+/// Keep a detached session running in the background until its shell exits
+///
+/// A forked holder keeps the pty master open, as closing it would hang the shell up.
+fn keep_detached(session: &Session, user: String, bus: impl FnOnce() -> Result<Bus>) -> Result<()> {
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            eprintln!("detached from {} (holder pid {})", session.unit(), child);
+            Ok(())
+        }
+        ForkResult::Child => {
+            let _ = setsid();
+            if let Ok(null) = open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+                for fd in 0..3 {
+                    let _ = dup2(null, fd);
+                }
+            }
+            let registration = register(session, user);
+            let ret = detach::hold(session.master(), session.unit()).and_then(|_| {
+                shell::exit_status(&bus()?, session.unit(), Some(shell::EXIT_TIMEOUT), None)
+            });
+            drop(registration);
+            process::exit(ret.map_or(1, |_| 0));
+        }
+    }
+}
+
+/// Forward the terminal to pty `master` until it ends, restoring the terminal afterwards
+fn forward(builder: PTYForwardBuilder, master: RawFd, init: Pid) -> Result<SessionEnd> {
+    let mut f = builder.build(master)?;
+    match f.watch_init(init) {
+        // pidfd is unsupported before Linux 5.3, rely on the hangup alone
+        Ok(()) | Err(Error::Sys(Errno::ENOSYS)) => {}
+        Err(e) => return Err(e),
+    }
+    f.wait()
+}
+
+/// Enter the mount namespace of systemd `init`, returning whether that worked
+///
+/// Without privileges over it, as from outside a user namespace, angea stays where it is.
+fn enter_systemd(init: Pid) -> bool {
+    systemd::enter_mount_ns(init)
+        .inspect_err(|e| eprintln!("staying outside the mount namespace of systemd: {}", e))
+        .is_ok()
+}
+
+/// Unit of a shell given as its unit, pty path or pty number
+fn shell_unit(target: String) -> String {
+    if target.starts_with("angea-shell@") {
+        target
+    } else {
+        shell::unit_name(&target)
+    }
+}
+
+/// Stop one shell session, given as its unit, pty path or pty number
+fn stop(target: Option<String>) -> Result<()> {
+    let target = target.ok_or_else(|| {
+        eprintln!("usage: angea stop <pts>");
+        Errno::EINVAL
+    })?;
+    let unit = shell_unit(target);
+    let init = systemd::get_running()?.ok_or(Error::NotRunning)?;
+    // The forwarding angea sees the hangup and cleans up after the shell
+    Bus::namespace(init)?.stop_unit(&unit)?;
+    println!("stopped {}", unit);
+    Ok(())
+}
+
+/// Attach to a detached shell, given as its unit, pty path or pty number
+fn attach(target: Option<String>) -> Result<()> {
+    let target = target.ok_or_else(|| {
+        eprintln!("usage: angea attach <pts>");
+        Errno::EINVAL
+    })?;
+    let unit = shell_unit(target);
+    let init = systemd::get_running()?.ok_or(Error::NotRunning)?;
+    // Detached sessions are held in the registry of the mount namespace of systemd
+    enter_systemd(init);
+    let master = detach::attach(&unit).inspect_err(|_| {
+        eprintln!("{} is not a detached session", unit);
+    })?;
+    let end = forward(PTYForward::builder(), master, init);
+    close(master)?;
+    match end? {
+        SessionEnd::Hangup => {}
+        SessionEnd::InitExited => eprintln!("systemd exited, session closed"),
+        SessionEnd::Detached => eprintln!("detached from {}", unit),
+        SessionEnd::Idle => eprintln!("idle for too long, detached from {}", unit),
+        // The holder still keeps the session, as on detach
+        SessionEnd::Signaled(sig) => eprintln!("{}, detached from {}", sig, unit),
+    }
+    Ok(())
+}
+
+fn exec_cmd(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut user = None;
+    let mut command = Vec::new();
+    let mut bus_fd = env::var("ANGEA_BUS_FD").ok();
+    let mut start_timeout = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                command.extend(args.by_ref());
+                break;
+            }
+            "--bus-fd" => bus_fd = Some(flag_value(&arg, args.next())?),
+            "--start-timeout" => {
+                start_timeout = Some(parse_duration(&flag_value(&arg, args.next())?)?);
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option: {}", arg);
+                return Err(Errno::EINVAL.into());
+            }
+            _ => user = Some(parse_user(&arg)?),
+        }
+    }
+    if command.is_empty() {
+        eprintln!("usage: angea exec [options] [user] -- <command> [args]");
+        return Err(Errno::EINVAL.into());
+    }
+    let opts = ShellOptions {
+        user,
+        start_timeout,
+        ..ShellOptions::from_env()?
+    };
+    let (init, bus) = connect(parse_bus_fd(bus_fd)?, false)?;
+    // Register where shell sessions do, in the registry systemd sees
+    enter_systemd(init);
+    // Blocked before the unit starts, so none arriving meanwhile is lost
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGHUP);
+    signals.add(Signal::SIGQUIT);
+    signals.thread_block()?;
+    let mut signal_fd =
+        SignalFd::with_flags(&signals, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+    let unit = shell::start_exec(&bus, &command, &opts)?;
+    // Without a pty of its own, like `-` for a missing tty in ps
+    let registration = register_unit(&unit, "-", opts.user_or_root().to_string());
+    let status = shell::exit_status(&bus, &unit, None, Some(&mut signal_fd)).inspect_err(|_| {
+        let _ = bus.stop_unit(&unit);
+    });
+    drop(registration);
+    drop(bus);
+    process::exit(status?);
+}
+
+/// Boot systemd if needed and connect to its bus, through `bus_fd` if given
+fn connect(bus_fd: Option<RawFd>, recover: bool) -> Result<(Pid, Bus)> {
+    match bus_fd {
+        Some(fd) => {
+            let init = boot(&start_options_from_env()?)?.pid();
+            let bus = Bus::from_fd(fd, !Bus::is_private_socket(fd))?;
+            bus.ping()?;
+            Ok((init, bus))
+        }
+        None => {
+            let (outcome, bus) = boot_ready(&start_options_from_env()?, recover)?;
+            Ok((outcome.pid(), bus))
+        }
+    }
+}
+
+/// Parse the value of `--bus-fd`
+fn parse_bus_fd(fd: Option<String>) -> Result<Option<RawFd>> {
+    match fd {
+        Some(fd) => Ok(Some(fd.parse().map_err(|_| {
+            eprintln!("invalid bus fd: {}", fd);
+            Errno::EBADF
+        })?)),
+        None => Ok(None),
+    }
+}
+
+fn boot_cmd(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut opts = start_options_from_env()?;
+    let mut exit_on_idle = None;
+    let mut recover = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hardened" => opts
+                .mask_paths
+                .extend(HARDENED_MASK_PATHS.iter().map(|p| p.to_string())),
+            "--mask" => opts.mask_paths.push(flag_value(&arg, args.next())?),
+            "--rootfs" => opts.rootfs = Some(flag_value(&arg, args.next())?),
+            "--overlay" => opts.overlay = true,
+            "--overlay-upper" => {
+                opts.overlay = true;
+                opts.overlay_upper = Some(flag_value(&arg, args.next())?);
+            }
+            "--overlay-work" => {
+                opts.overlay = true;
+                opts.overlay_work = Some(flag_value(&arg, args.next())?);
+            }
+            "--volume" => opts
+                .volumes
+                .push(parse_volume(&flag_value(&arg, args.next())?)?),
+            "--uid-map" => opts
+                .uid_map
+                .push(parse_id_map(&flag_value(&arg, args.next())?)?),
+            "--gid-map" => opts
+                .gid_map
+                .push(parse_id_map(&flag_value(&arg, args.next())?)?),
+            "--private-network" => opts.private_network = true,
+            "--share-sys" => opts.share_sys = true,
+            "--share-cgroup" => opts.share_cgroup = true,
+            "--private-run" => opts.run_size = Some(parse_size(&flag_value(&arg, args.next())?)?),
+            "--private-tmp" => opts.tmp_size = Some(parse_size(&flag_value(&arg, args.next())?)?),
+            "--hostname" => opts.hostname = Some(flag_value(&arg, args.next())?),
+            "--systemd-log-level" => {
+                let level = flag_value(&arg, args.next())?;
+                opts.log_level = Some(one_of(level, systemd::LOG_LEVELS)?);
+            }
+            "--systemd-log-target" => {
+                let target = flag_value(&arg, args.next())?;
+                opts.log_target = Some(one_of(target, systemd::LOG_TARGETS)?);
+            }
+            "--exit-on-idle" => {
+                exit_on_idle = Some(parse_duration(&flag_value(&arg, args.next())?)?);
+            }
+            "--recover" => recover = true,
+            _ => {
+                eprintln!("unknown option: {}", arg);
+                return Err(Errno::EINVAL.into());
+            }
+        }
+    }
+    if opts.overlay && opts.rootfs.is_none() {
+        eprintln!("--overlay needs --rootfs as its lower directory");
+        return Err(Errno::EINVAL.into());
+    }
+    if opts.overlay_upper.is_some() != opts.overlay_work.is_some() {
+        eprintln!("--overlay-upper and --overlay-work must be given together");
+        return Err(Errno::EINVAL.into());
+    }
+    let outcome = if recover {
+        boot_ready(&opts, true)?.0
+    } else {
+        boot(&opts)?
+    };
+    match outcome {
+        BootOutcome::AlreadyRunning(pid) => println!("systemd is already running (pid {})", pid),
+        BootOutcome::Started(pid) => println!("systemd started (pid {})", pid),
+    }
+    if let Some(idle) = exit_on_idle {
+        shutdown_on_idle(outcome.pid(), idle)?;
+    }
+    Ok(())
+}
+
+/// Start options configured by angea's own environment
+fn start_options_from_env() -> Result<StartOptions> {
+    start_options(env::var("ANGEA_INIT_ENVS").ok().as_deref())
+}
+
+/// Start options for the `ANGEA_INIT_ENVS` value `init_envs`, if set
+///
+/// systemd gets only the variables of `init_envs`, never angea's or the shells'.
+/// Fails listing the entries which are not `KEY=VALUE`.
+fn start_options(init_envs: Option<&str>) -> Result<StartOptions> {
+    let mut opts = StartOptions::default();
+    if let Some(s) = init_envs {
+        opts.environment = shell::parse_envs("ANGEA_INIT_ENVS", s)?;
+    }
+    Ok(opts)
+}
+
+/// Boot systemd and connect to its bus
+///
+/// With `recover`, a systemd which was already running but never answers on
+/// the bus is considered half-booted, it is killed and booted again.
+fn boot_ready(opts: &StartOptions, recover: bool) -> Result<(BootOutcome, Bus)> {
+    recover_boot(
+        recover,
+        || boot(opts),
+        |pid| Bus::wait_ready(pid, READY_TIMEOUT),
+        |pid| systemd::stop(pid, ShutdownMethod::default(), &ShutdownTimeouts::default()),
+    )
+}
+
+/// `boot_ready` with its steps passed in: `boot`, then `probe` whether the
+/// booted systemd answers, and with `recover`, `stop` it if not to boot again
+fn recover_boot<B, S>(
+    recover: bool,
+    mut boot: impl FnMut() -> Result<BootOutcome>,
+    mut probe: impl FnMut(Pid) -> Result<B>,
+    stop: impl FnOnce(Pid) -> Result<S>,
+) -> Result<(BootOutcome, B)> {
+    let outcome = boot()?;
+    match (probe(outcome.pid()), outcome) {
+        (Ok(bus), _) => Ok((outcome, bus)),
+        (Err(_), BootOutcome::AlreadyRunning(pid)) if recover => {
+            eprintln!(
+                "systemd (pid {}) does not answer on the bus, restarting it",
+                pid
+            );
+            stop(pid)?;
+            let outcome = boot()?;
+            Ok((outcome, probe(outcome.pid())?))
+        }
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// Stay in foreground and shut systemd `init` down once no shell session was active for `idle`
+fn shutdown_on_idle(init: Pid, idle: Duration) -> Result<()> {
+    let bus = Bus::wait_ready(init, READY_TIMEOUT)?;
+    let mut last_active = Instant::now();
+    loop {
+        thread::sleep(idle.min(IDLE_POLL_INTERVAL));
+        if !systemd::is_alive(init) {
+            match systemd::reap(init) {
+                Some(WaitStatus::Exited(_, code)) => eprintln!("systemd exited with {}", code),
+                Some(WaitStatus::Signaled(_, sig, _)) => eprintln!("systemd killed by {}", sig),
+                _ => eprintln!("systemd exited"),
+            }
+            return Ok(());
+        }
+        if bus.active_sessions()? > 0 {
+            last_active = Instant::now();
+        } else if last_active.elapsed() >= idle {
+            eprintln!("no session for {:?}, shutting down systemd", idle);
+            systemd::stop(
+                init,
+                ShutdownMethod::default(),
+                &ShutdownTimeouts::default(),
+            )?;
+            return Ok(());
+        }
+    }
 }
 
-fn shell(user: Option<String>) -> Result<()> {
-    boot()?;
+/// Boot systemd with `opts`, unless one is already running
+///
+/// A newly started systemd is waited for until it answers on its bus, up to
+/// `READY_TIMEOUT`, so it is usable once `boot` returns.
+///
+/// # Errors
+///
+/// The error of the last connection attempt if the new systemd never answers.
+pub fn boot(opts: &StartOptions) -> Result<BootOutcome> {
+    let outcome = boot_outcome(systemd::get_running()?, || systemd::start(opts))?;
+    if let BootOutcome::Started(pid) = outcome {
+        Bus::wait_ready(pid, READY_TIMEOUT)?;
+    }
+    Ok(outcome)
+}
+
+/// Keep the `running` systemd, or `start` one if there is none
+fn boot_outcome(running: Option<Pid>, start: impl FnOnce() -> Result<Pid>) -> Result<BootOutcome> {
+    match running {
+        Some(pid) => Ok(BootOutcome::AlreadyRunning(pid)),
+        None => start().map(BootOutcome::Started),
+    }
+}
+
+/// Parse a duration like `500ms`, `30s`, `5m` or `1h`, seconds if no unit
+fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (n, unit) = value.split_at(split);
+    let n: u64 = n.parse().map_err(|_| {
+        eprintln!("invalid duration: {}", value);
+        Errno::EINVAL
+    })?;
+    let duration = match unit {
+        "ms" => Some(Duration::from_millis(n)),
+        "" | "s" => Some(Duration::from_secs(n)),
+        "m" => n.checked_mul(60).map(Duration::from_secs),
+        "h" => n.checked_mul(3600).map(Duration::from_secs),
+        _ => None,
+    };
+    duration.ok_or_else(|| {
+        eprintln!("invalid duration: {}", value);
+        Error::Sys(Errno::EINVAL)
+    })
+}
+
+/// Check a user or group given as name or numeric id
+fn parse_user(value: &str) -> Result<String> {
+    if !is_user_or_id(value) {
+        eprintln!("invalid user or group: {}", value);
+        return Err(Errno::EINVAL.into());
+    }
+    Ok(value.to_string())
+}
+
+/// Parse a byte count with an optional K, M, G or T suffix, 1024-based like systemd
+fn parse_bytes(value: &str) -> Result<u64> {
+    let (digits, shift) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        Some('T') => (&value[..value.len() - 1], 40),
+        _ => (value, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| {
+            eprintln!("invalid size: {}", value);
+            Error::Sys(Errno::EINVAL)
+        })
+}
+
+/// Check a tmpfs size like `64m`, `1g` or `10%`
+fn parse_size(value: &str) -> Result<String> {
+    let digits = value.trim_end_matches(['k', 'm', 'g', '%']);
+    if digits.len() + 1 < value.len()
+        || digits.is_empty()
+        || !digits.bytes().all(|b| b.is_ascii_digit())
+    {
+        eprintln!("invalid size: {}", value);
+        return Err(Errno::EINVAL.into());
+    }
+    Ok(value.to_string())
+}
 
-    let user = user.unwrap_or_else(|| String::from("root"));
-    let master = get_pty(user)?;
-    let mut f = PTYForward::new(master)?;
-    f.wait()?;
+/// Parse a `host:path[:ro|rw]` volume given on the command line
+fn parse_volume(value: &str) -> Result<(String, String, bool)> {
+    let mut parts = value.split(':');
+    let volume = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(path), mode, None) if path.starts_with('/') && !host.is_empty() => {
+            match mode {
+                None | Some("rw") => Some((host.to_string(), path.to_string(), false)),
+                Some("ro") => Some((host.to_string(), path.to_string(), true)),
+                Some(_) => None,
+            }
+        }
+        _ => None,
+    };
+    volume.ok_or_else(|| {
+        eprintln!("invalid volume: {}, expected host:path[:ro|rw]", value);
+        Error::Sys(Errno::EINVAL)
+    })
+}
+
+/// Parse an `inside:outside:count` id range given on the command line
+fn parse_id_map(value: &str) -> Result<(u32, u32, u32)> {
+    let ids = value
+        .split(':')
+        .map(|id| id.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>();
+    match ids.as_deref() {
+        Some(&[inside, outside, count]) if count > 0 => Ok((inside, outside, count)),
+        _ => {
+            eprintln!("invalid id map: {}, expected inside:outside:count", value);
+            Err(Errno::EINVAL.into())
+        }
+    }
+}
+
+/// Parse a capability name given on the command line
+fn parse_capability(name: &str) -> Result<u64> {
+    capability::parse(name).ok_or_else(|| {
+        eprintln!("unknown capability: {}", name);
+        Error::Sys(Errno::EINVAL)
+    })
+}
+
+/// Check `value` is one of `accepted`
+fn one_of(value: String, accepted: &[&str]) -> Result<String> {
+    if accepted.contains(&value.as_str()) {
+        Ok(value)
+    } else {
+        eprintln!(
+            "invalid value {}, expected one of: {}",
+            value,
+            accepted.join(", ")
+        );
+        Err(Errno::EINVAL.into())
+    }
+}
+
+/// Take the value following a flag
+fn flag_value(flag: &str, value: Option<String>) -> Result<String> {
+    value.ok_or_else(|| {
+        eprintln!("missing value for {}", flag);
+        Error::Sys(Errno::EINVAL)
+    })
+}
+
+fn shutdown(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut method = ShutdownMethod::default();
+    let mut timeouts = ShutdownTimeouts::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => timeouts.poweroff = parse_duration(&flag_value(&arg, args.next())?)?,
+            "--term-timeout" => timeouts.term = parse_duration(&flag_value(&arg, args.next())?)?,
+            "--kill-timeout" => timeouts.kill = parse_duration(&flag_value(&arg, args.next())?)?,
+            "--shutdown-method" => {
+                let value = flag_value(&arg, args.next())?;
+                method = value.parse().inspect_err(|_| {
+                    eprintln!("unknown shutdown method: {}", value);
+                })?;
+            }
+            _ => {
+                eprintln!("unknown option: {}", arg);
+                return Err(Errno::EINVAL.into());
+            }
+        }
+    }
+    match systemd::shutdown(method, &timeouts)? {
+        ShutdownOutcome::NotRunning => println!("systemd is not running"),
+        ShutdownOutcome::Exited => println!("systemd exited"),
+        ShutdownOutcome::Killed => println!("systemd did not exit in time and was killed"),
+    }
     Ok(())
 }
 
-fn boot() -> Result<()> {
-    if systemd::get_running()?.is_none() {
-        systemd::start()?;
+/// Copy a file between host and namespace, `:`-prefixed paths are inside
+fn cp(src: Option<String>, dst: Option<String>) -> Result<()> {
+    let (src, dst) = match (src, dst) {
+        (Some(src), Some(dst)) => (src, dst),
+        _ => {
+            eprintln!("usage: angea cp <src> <dst>");
+            return Err(Errno::EINVAL.into());
+        }
+    };
+    let init = systemd::get_running()?.ok_or(Error::NotRunning)?;
+    match (src.strip_prefix(':'), dst.strip_prefix(':')) {
+        (None, Some(inner)) => copy::copy_in(init, &src, inner),
+        (Some(inner), None) => copy::copy_out(init, inner, &dst),
+        _ => {
+            eprintln!("exactly one of <src> and <dst> must be prefixed with `:`");
+            Err(Errno::EINVAL.into())
+        }
+    }
+}
+
+fn status() -> Result<()> {
+    match systemd::get_running()? {
+        Some(pid) => println!(
+            "systemd is running (pid {}), up {}",
+            pid,
+            format_duration(systemd::uptime(pid)?)
+        ),
+        None => println!("systemd is not running"),
     }
     Ok(())
 }
 
-fn shutdown() -> Result<()> {
-    systemd::shutdown()
+/// Format a duration like `1h 2m 3s`, leaving out leading zero units
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {}m {}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// List shell sessions known to systemd
+fn list() -> Result<()> {
+    let init = systemd::get_running()?.ok_or(Error::NotRunning)?;
+    let bus = Bus::namespace(init)?;
+    let shells = shell::list_shells(&bus)?;
+    if shells.is_empty() {
+        println!("no shell session");
+        return Ok(());
+    }
+    println!("{:<6} {:<16} {:<20} PID", "PTS", "USER", "STATE");
+    for s in shells {
+        println!("{:<6} {:<16} {:<20} {}", s.pts, s.user, s.state, s.main_pid);
+    }
+    Ok(())
+}
+
+/// Restore stdin and stdout after an angea was killed with the terminal in raw mode
+fn reset() -> Result<()> {
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO] {
+        ptyfwd::reset_terminal(fd)?;
+    }
+    Ok(())
+}
+
+/// Drop registry records of sessions which ended without cleaning up
+fn prune() -> Result<()> {
+    let init = systemd::get_running()?.ok_or(Error::NotRunning)?;
+    let bus = if enter_systemd(init) {
+        Bus::private()?
+    } else {
+        Bus::namespace(init)?
+    };
+    for record in registry::prune(&bus)? {
+        println!(
+            "pruned {} ({}, pid {})",
+            record.unit, record.pts, record.pid
+        );
+    }
+    Ok(())
+}
+
+fn probe() -> Result<()> {
+    let path = match systemd::resolve_path(None) {
+        Ok(path) => path,
+        Err(_) => {
+            println!("no systemd in {}", systemd::CANDIDATE_PATHS.join(", "));
+            return Ok(());
+        }
+    };
+    if systemd::is_executable(&path) {
+        println!("{} (executable)", path);
+    } else {
+        println!("{} (not executable)", path);
+    }
+    Ok(())
 }
 
 fn help() -> Result<()> {
@@ -48,11 +929,213 @@ fn help() -> Result<()> {
         "
 Usage: angea <command> [more]
 Command:
-    boot            Start systemd
-    shell [user]    Open a shell in systemd. [Default: root]
-    shutdown        Kill running systemd
+    boot [options]  Start systemd
+    shell [options] [user] [-- <command> [args]]
+                    Open a shell in systemd as user, a name or numeric uid. [Default: root]
+    attach <pts>    Attach to a detached shell
+    stop <pts>      Stop a shell session, leaving systemd running
+    exec [options] [user] -- <command> [args]
+                    Run a command in systemd with angea's stdio, exit with its status
+    shutdown [options]
+                    Stop running systemd
+    status          Show whether systemd is running, its pid and uptime
+    list            List shell sessions with their pty, user, state and pid
+    probe           Show which systemd binary boot would run
+    prune           Clean up shell sessions which did not exit cleanly
+    reset           Restore a terminal left raw by a killed angea
+    cp <src> <dst>  Copy a file into or out of systemd, `:/path` is inside
     help            This message
+Boot Options:
+    --hardened      Mask sensitive /proc and /sys paths
+    --mask <path>   Mask an extra path, may be repeated
+    --rootfs <path> Boot the systemd of another root directory
+    --overlay       Boot from a throwaway overlay of --rootfs, left untouched
+    --overlay-upper <path>
+                    Keep the overlay changes in this directory instead
+    --overlay-work <path>
+                    Work directory of the overlay, on the same filesystem
+    --volume <host:path[:ro]>
+                    Bind a host directory into the namespace, may be repeated
+    --uid-map <inside:outside:count>
+                    Boot in a new user namespace with this uid range, may be
+                    repeated
+    --gid-map <inside:outside:count>
+                    Boot in a new user namespace with this gid range, may be
+                    repeated
+    --private-network
+                    Boot in a new network namespace with only loopback
+    --share-sys     Keep the host /sys instead of mounting sysfs and cgroup2
+    --share-cgroup  Keep the host cgroup namespace, for kernels without one
+    --private-run <size>
+                    Mount a fresh tmpfs of at most size on /run, e.g. 64m
+    --private-tmp <size>
+                    Mount a fresh tmpfs of at most size on /tmp, e.g. 64m
+    --hostname <name>
+                    Hostname of the booted system, the host's by default
+    --systemd-log-level <level>
+                    Log level of systemd, e.g. debug
+    --systemd-log-target <target>
+                    Log target of systemd, e.g. console
+    --exit-on-idle <duration>
+                    Stay in foreground, shut systemd down after no shell or
+                    exec session was active for the duration, e.g. 30s, 5m, 1h
+    --recover       Wait for systemd on the bus, restart it if it never answers
+Shutdown Options:
+    --shutdown-method <systemd|sigterm>
+                    systemd: SIGRTMIN+3, then SIGKILL [Default]
+                    sigterm: SIGTERM, then SIGRTMIN+3, then SIGKILL
+    --timeout <dur> Time to power off after SIGRTMIN+3 before SIGKILL [Default: 10s]
+    --term-timeout <dur>
+                    Time to exit after SIGTERM before SIGRTMIN+3 [Default: 5s]
+    --kill-timeout <dur>
+                    Time to exit after SIGKILL before giving up [Default: 5s]
+Shell Options:
+    --documentation <uri>
+                    Documentation URI of the shell unit, may be repeated
+    --working-directory <path>
+                    Start the shell in path instead of the home of the user
+    --environment-file <[-]path>
+                    Read variables from path inside systemd, - ignores a
+                    missing file, may be repeated
+    --cap-add <cap> Grant an ambient capability, may be repeated
+    --cap-drop <cap>
+                    Drop a capability from the bounding set, may be repeated
+    --resize-interval <ms>
+                    Also poll the terminal size, for terminals not sending SIGWINCH
+    --idle-timeout <dur>
+                    Detach once nothing was typed or printed for the duration
+    --bus-fd <fd>   Talk to systemd over this inherited bus socket [Env: ANGEA_BUS_FD]
+    --start-timeout <dur>
+                    Time the shell unit may take to start [Default: 30s]
+    --group <group> Run the shell with this primary group, a name or numeric gid
+    --supplementary-group <group>
+                    Also run the shell in this group, may be repeated
+    --no-tty-reset  Keep the terminal settings an earlier session left on the pty
+    --pam-name <service>
+                    Open a PAM login session with this service, e.g. login
+    --slice <name>  Run the shell in this slice, e.g. angea.slice
+    --memory-max <bytes>
+                    Limit the memory of the shell, e.g. 512M, 2G
+    --cpu-quota <percent>
+                    Limit the CPU time of the shell in percent of one CPU, e.g. 50%
+    --recover       Restart systemd if it never answers on the bus
+    --no-detach     Do not detach on Ctrl+] typed three times
+    --no-setns      Open the pty from the host mount namespace, not systemd's
+    --typescript <file>
+                    Append the session output to file, like script(1) [Env: ANGEA_TYPESCRIPT]
+    --asciicast <file>
+                    Write the session output to file as asciinema v2 cast [Env: ANGEA_ASCIICAST]
 "
     ));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::ffi::CString;
+    use std::fs;
+
+    #[test]
+    fn boot_keeps_running_systemd() {
+        let proc = systemd::tests::fixture("boot-running");
+        systemd::tests::add_process(&proc, 40, b"/lib/systemd/systemd\0", 2);
+        let running = systemd::get_running_in(&proc, &proc.join("systemd.pid")).unwrap();
+        let outcome = boot_outcome(running, || panic!("started a second systemd")).unwrap();
+        assert_eq!(outcome, BootOutcome::AlreadyRunning(Pid::from_raw(40)));
+        fs::remove_dir_all(&proc).unwrap();
+    }
+
+    #[test]
+    fn boot_starts_missing_systemd() {
+        let proc = systemd::tests::fixture("boot-missing");
+        // Only the init of angea's own namespace
+        systemd::tests::add_process(&proc, 1, b"/usr/lib/systemd/systemd\0", 1);
+        let running = systemd::get_running_in(&proc, &proc.join("systemd.pid")).unwrap();
+        let outcome = boot_outcome(running, || Ok(Pid::from_raw(50))).unwrap();
+        assert_eq!(outcome, BootOutcome::Started(Pid::from_raw(50)));
+        assert!(boot_outcome(running, || Err(Errno::EPERM.into())).is_err());
+        fs::remove_dir_all(&proc).unwrap();
+    }
+
+    /// `recover_boot` of a systemd `stale`, alive but never answering on the bus,
+    /// returning its outcome along with the pids stopped
+    fn recover_stale(recover: bool, stale: Pid) -> (Result<BootOutcome>, Vec<Pid>) {
+        let running = Cell::new(Some(stale));
+        let mut stopped = Vec::new();
+        let outcome = recover_boot(
+            recover,
+            || boot_outcome(running.get(), || Ok(Pid::from_raw(200))),
+            |pid| {
+                if pid == stale {
+                    Err(Error::Timeout("systemd on the bus"))
+                } else {
+                    Ok(())
+                }
+            },
+            |pid| {
+                stopped.push(pid);
+                running.set(None);
+                Ok(())
+            },
+        );
+        (outcome.map(|(outcome, ())| outcome), stopped)
+    }
+
+    #[test]
+    fn recover_restarts_systemd_without_bus() {
+        let stale = Pid::from_raw(100);
+        let (outcome, stopped) = recover_stale(true, stale);
+        assert_eq!(outcome.unwrap(), BootOutcome::Started(Pid::from_raw(200)));
+        assert_eq!(stopped, [stale]);
+    }
+
+    #[test]
+    fn no_recover_leaves_systemd_without_bus() {
+        let (outcome, stopped) = recover_stale(false, Pid::from_raw(100));
+        assert!(matches!(outcome, Err(Error::Timeout(_))));
+        assert!(stopped.is_empty());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        for invalid in [
+            "",
+            "m",
+            "5d",
+            "-1s",
+            "18446744073709551615m",
+            "5124095576030432h",
+        ] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn init_envs_are_key_value() {
+        let opts = start_options(Some("LANG=C.UTF-8, ,TZ=UTC,")).unwrap();
+        assert_eq!(opts.environment, ["LANG=C.UTF-8", "TZ=UTC"]);
+        assert!(start_options(Some("LANG=C,TZ")).is_err());
+        assert!(start_options(None).unwrap().environment.is_empty());
+    }
+
+    #[test]
+    fn only_init_envs_reach_init() {
+        let opts = start_options(Some("ANGEA_TEST_INIT=1")).unwrap();
+        let environ = systemd::init_environ(&opts).unwrap();
+        assert_eq!(environ, [CString::new("ANGEA_TEST_INIT=1").unwrap()]);
+    }
+
+    #[test]
+    fn host_environment_does_not_reach_init() {
+        assert!(env::var_os("PATH").is_some());
+        let opts = start_options(None).unwrap();
+        assert!(systemd::init_environ(&opts).unwrap().is_empty());
+    }
+}
@@ -1,5 +1,10 @@
+use std::process::ExitCode;
+
 use angea::cmd;
 
-fn main() {
-   cmd();
+fn main() -> ExitCode {
+   match cmd() {
+      Ok(()) => ExitCode::SUCCESS,
+      Err(_) => ExitCode::FAILURE,
+   }
 }
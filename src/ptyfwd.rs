@@ -0,0 +1,970 @@
+use std::fs::File;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use nix::sys::epoll::{self, EpollEvent, EpollFlags, EpollOp};
+use nix::sys::signal::{sigprocmask, SigmaskHow, Signal};
+use nix::sys::signalfd::{SigSet, SignalFd};
+use nix::sys::stat::Mode;
+use nix::sys::termios::{
+    self, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg, SpecialCharacterIndices,
+    Termios,
+};
+use nix::unistd::{close, isatty, read, write, Pid};
+
+use crate::error::Result;
+use crate::record::{Format, Recorder};
+
+/// Why a forwarding session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The shell hung up its terminal
+    Hangup,
+    /// The init of the namespace exited
+    InitExited,
+    /// The user typed the escape sequence, the shell keeps running
+    Detached,
+    /// Nothing was typed or printed for the idle timeout, the shell keeps running
+    Idle,
+    /// angea got SIGTERM, SIGHUP or SIGQUIT, the shell keeps running for the caller to stop it
+    Signaled(Signal),
+}
+
+/// Default size of the buffer reads go through
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Default escape sequence, Ctrl+] three times like machinectl
+pub const DEFAULT_ESCAPE: &[u8] = b"\x1d\x1d\x1d";
+
+pub struct PTYForward {
+    epoll: RawFd,
+    master: RawFd,
+    in_fd: RawFd,
+    input: Input,
+    out_fd: RawFd,
+    signal_fd: SignalFd,
+    /// Signal mask before `build`, restored on drop
+    sig_origin: SigSet,
+    init_fd: Option<RawFd>,
+    in_origin: Option<Termios>,
+    out_origin: Option<Termios>,
+    /// Terminal the window size is taken from, see `size_source`
+    size_fd: Option<RawFd>,
+    /// `/dev/tty` if opened as `size_fd`, closed on drop
+    tty_fd: Option<RawFd>,
+    resize_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    buffer_size: usize,
+    last_size: Option<(u16, u16)>,
+    /// Master output not yet written to `out_fd`
+    out_pending: Vec<u8>,
+    /// Input not yet written to master
+    in_pending: Vec<u8>,
+    /// Events master is in the epoll set for, empty if it is not, see `update_events`
+    master_events: EpollFlags,
+    /// Whether `out_fd` is in the epoll set
+    output_watched: bool,
+    /// Whether `in_fd` is in the epoll set
+    input_watched: bool,
+    escape: Option<Vec<u8>>,
+    /// Length of the escape sequence prefix typed last
+    escape_matched: usize,
+    /// Recording of everything master wrote
+    recorder: Option<Recorder>,
+}
+
+/// How input is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Input {
+    /// Through epoll
+    Polled,
+    /// On every turn, for regular files epoll does not take
+    Unpolled,
+    /// Input hit end of file, which was passed on to the shell
+    Closed,
+}
+
+const STDIN_EVENT: u64 = 1;
+
+const MASTER_EVENT: u64 = 2;
+
+const SIGNAL_EVENT: u64 = 3;
+
+const INIT_EVENT: u64 = 4;
+
+const OUTPUT_EVENT: u64 = 5;
+
+/// Builder of `PTYForward`, forwarding stdin/stdout unless overridden
+#[derive(Debug)]
+pub struct PTYForwardBuilder {
+    in_fd: RawFd,
+    out_fd: RawFd,
+    resize_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    window_size: Option<(u16, u16)>,
+    buffer_size: usize,
+    escape: Option<Vec<u8>>,
+    recording: Option<(File, Format)>,
+}
+
+impl Default for PTYForwardBuilder {
+    fn default() -> Self {
+        PTYForwardBuilder {
+            in_fd: libc::STDIN_FILENO,
+            out_fd: libc::STDOUT_FILENO,
+            resize_interval: None,
+            idle_timeout: None,
+            window_size: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            escape: Some(DEFAULT_ESCAPE.to_vec()),
+            recording: None,
+        }
+    }
+}
+
+impl PTYForwardBuilder {
+    /// Forward `fd` to master instead of stdin
+    pub fn input(mut self, fd: RawFd) -> Self {
+        self.in_fd = fd;
+        self
+    }
+
+    /// Forward master to `fd` instead of stdout
+    pub fn output(mut self, fd: RawFd) -> Self {
+        self.out_fd = fd;
+        self
+    }
+
+    /// Also poll the output window size every `interval`, for terminals not sending SIGWINCH
+    pub fn resize_interval(mut self, interval: Duration) -> Self {
+        self.resize_interval = Some(interval);
+        self
+    }
+
+    /// End the session with `SessionEnd::Idle` once neither input nor master had data for `timeout`
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Give master a window of `rows` x `cols` instead of mirroring a terminal
+    ///
+    /// For input and output which are no terminals, like a web terminal whose size
+    /// comes from the client. Later sizes are set with `PTYForward::set_window_size`.
+    pub fn window_size(mut self, rows: u16, cols: u16) -> Self {
+        self.window_size = Some((rows, cols));
+        self
+    }
+
+    /// Read up to `size` bytes at once, larger buffers need fewer syscalls on busy output
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size.max(1);
+        self
+    }
+
+    /// Detach when `sequence` is typed, never if `None`
+    ///
+    /// The sequence is still forwarded to the shell as typed.
+    pub fn escape(mut self, sequence: Option<Vec<u8>>) -> Self {
+        self.escape = sequence.filter(|s| !s.is_empty());
+        self
+    }
+
+    /// Also write master output to `file`, between a start and a done line with the time
+    ///
+    /// Input is not recorded, though the echo of the shell usually shows it.
+    pub fn typescript(mut self, file: File) -> Self {
+        self.recording = Some((file, Format::Typescript));
+        self
+    }
+
+    /// Record master output to `file` as an asciinema v2 cast instead
+    ///
+    /// The terminal size in the header is the one at start, 80x24 if output is no terminal.
+    pub fn asciicast(mut self, file: File) -> Self {
+        self.recording = Some((file, Format::Asciicast));
+        self
+    }
+
+    /// Set up epoll and termios to forward `master`
+    ///
+    /// Termios is only switched to raw mode for fds which are terminals,
+    /// so pipes or socketpairs may stand in for stdin/stdout. `master` is made non-blocking.
+    pub fn build(self, master: RawFd) -> Result<PTYForward> {
+        let PTYForwardBuilder {
+            in_fd,
+            out_fd,
+            resize_interval,
+            idle_timeout,
+            window_size,
+            buffer_size,
+            escape,
+            recording,
+        } = self;
+        let epoll = epoll::epoll_create()?;
+
+        let mut stdin_event = EpollEvent::new(EpollFlags::EPOLLIN, STDIN_EVENT);
+        let mut master_event = EpollEvent::new(EpollFlags::EPOLLIN, MASTER_EVENT);
+        let input = match epoll::epoll_ctl(epoll, EpollOp::EpollCtlAdd, in_fd, &mut stdin_event) {
+            Ok(()) => Input::Polled,
+            // stdin redirected from a file, which is always readable
+            Err(Errno::EPERM) => Input::Unpolled,
+            Err(e) => return Err(e.into()),
+        };
+        epoll::epoll_ctl(epoll, EpollOp::EpollCtlAdd, master, &mut master_event)?;
+
+        let mut sig_set = SigSet::empty();
+        sig_set.add(Signal::SIGWINCH);
+        sig_set.add(Signal::SIGINT);
+        sig_set.add(Signal::SIGTERM);
+        sig_set.add(Signal::SIGHUP);
+        sig_set.add(Signal::SIGQUIT);
+        let mut sig_origin = SigSet::empty();
+        sigprocmask(SigmaskHow::SIG_BLOCK, Some(&sig_set), Some(&mut sig_origin))?;
+        let signal_fd = SignalFd::new(&sig_set)?;
+        let mut sig_event = EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL_EVENT);
+        epoll::epoll_ctl(
+            epoll,
+            EpollOp::EpollCtlAdd,
+            signal_fd.as_raw_fd(),
+            &mut sig_event,
+        )?;
+
+        PTYForward::set_nonblock(in_fd, true)?;
+        PTYForward::set_nonblock(master, true)?;
+        let in_origin = PTYForward::set_raw_termios(in_fd)?;
+        let out_origin = PTYForward::set_raw_termios(out_fd)?;
+        let (size_fd, tty_fd) = match window_size {
+            Some(_) => (None, None),
+            None => size_source(in_fd, out_fd)?,
+        };
+        let mut f = PTYForward {
+            epoll,
+            master,
+            in_fd,
+            input,
+            out_fd,
+            signal_fd,
+            sig_origin,
+            init_fd: None,
+            in_origin,
+            out_origin,
+            size_fd,
+            tty_fd,
+            resize_interval,
+            idle_timeout,
+            buffer_size,
+            last_size: None,
+            out_pending: Vec::new(),
+            in_pending: Vec::new(),
+            master_events: EpollFlags::EPOLLIN,
+            output_watched: false,
+            input_watched: input == Input::Polled,
+            escape,
+            escape_matched: 0,
+            recorder: None,
+        };
+        match window_size {
+            Some((rows, cols)) => f.set_window_size(rows, cols)?,
+            None => f.resize()?,
+        }
+        if let Some((file, format)) = recording {
+            let size = f.last_size.unwrap_or((24, 80));
+            f.recorder = Some(Recorder::start(file, format, size)?);
+        }
+        Ok(f)
+    }
+}
+
+impl PTYForward {
+    /// Forward between `master` and stdin/stdout
+    pub fn new(master: RawFd) -> Result<PTYForward> {
+        Self::builder().build(master)
+    }
+
+    pub fn builder() -> PTYForwardBuilder {
+        PTYForwardBuilder::default()
+    }
+
+    /// End the session as well when the init process `pid` exits
+    pub fn watch_init(&mut self, pid: Pid) -> Result<()> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+        if fd < 0 {
+            return Err(Errno::last().into());
+        }
+        let fd = fd as RawFd;
+        let mut init_event = EpollEvent::new(EpollFlags::EPOLLIN, INIT_EVENT);
+        if let Err(e) = epoll::epoll_ctl(self.epoll, EpollOp::EpollCtlAdd, fd, &mut init_event) {
+            close(fd)?;
+            return Err(e.into());
+        }
+        self.init_fd = Some(fd);
+        Ok(())
+    }
+
+    pub fn wait(&mut self) -> Result<SessionEnd> {
+        let mut events = [EpollEvent::empty(); 128];
+        let mut buf = vec![0; self.buffer_size];
+        let interval = self
+            .resize_interval
+            .map_or(-1, |d| d.as_millis().clamp(1, isize::MAX as u128) as isize);
+        let mut last_check = Instant::now();
+        let mut last_activity = Instant::now();
+        let end = 'epoll: loop {
+            let unpolled = self.input == Input::Unpolled && self.in_pending.is_empty();
+            if unpolled && self.forward_input(&mut buf, &mut last_activity)? {
+                break SessionEnd::Detached;
+            }
+            // Do not sleep while unpolled input is left
+            let mut timeout = if unpolled { 0 } else { interval };
+            if let Some(idle) = self.idle_timeout {
+                let left = idle.saturating_sub(last_activity.elapsed());
+                if left.is_zero() {
+                    break SessionEnd::Idle;
+                }
+                // Round up, so the wait does not end just short of the timeout
+                let ms = (left.as_micros().div_ceil(1000)).min(isize::MAX as u128) as isize;
+                timeout = if timeout < 0 { ms } else { timeout.min(ms) };
+            }
+            let n = match epoll::epoll_wait(self.epoll, &mut events, timeout) {
+                Ok(n) => n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let ready = &events[..n];
+
+            if let Some(interval) = self.resize_interval {
+                if last_check.elapsed() >= interval {
+                    last_check = Instant::now();
+                    self.resize()?;
+                }
+            }
+
+            for ev in ready {
+                match ev.data() {
+                    STDIN_EVENT if self.forward_input(&mut buf, &mut last_activity)? => {
+                        break 'epoll SessionEnd::Detached;
+                    }
+                    MASTER_EVENT => {
+                        if ev.events().contains(EpollFlags::EPOLLOUT) {
+                            self.flush_input()?;
+                        }
+                        if !self.master_events.contains(EpollFlags::EPOLLIN)
+                            || !ev.events().intersects(
+                                EpollFlags::EPOLLIN | EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR,
+                            )
+                        {
+                            continue;
+                        }
+                        // master => stdout
+                        match read(self.master, &mut buf) {
+                            Ok(n) => {
+                                last_activity = Instant::now();
+                                self.forward_output(&buf[..n])?;
+                            }
+                            Err(Errno::EWOULDBLOCK | Errno::EINTR) => continue,
+                            Err(Errno::EIO) => break 'epoll SessionEnd::Hangup,
+                            Err(e) => return Err(e.into()),
+                        };
+                    }
+                    SIGNAL_EVENT => {
+                        let signo = match self.signal_fd.read_signal()? {
+                            Some(info) => info.ssi_signo as i32,
+                            None => continue,
+                        };
+                        match Signal::try_from(signo) {
+                            Ok(Signal::SIGWINCH) => self.resize()?,
+                            // Like a terminal, interrupt the foreground job of the shell
+                            Ok(Signal::SIGINT) => {
+                                let c = self.control_char(SpecialCharacterIndices::VINTR, 0x03);
+                                self.in_pending.push(c);
+                                self.flush_input()?;
+                            }
+                            Ok(sig) => break 'epoll SessionEnd::Signaled(sig),
+                            Err(_) => {}
+                        }
+                    }
+                    INIT_EVENT => break 'epoll SessionEnd::InitExited,
+                    OUTPUT_EVENT => self.flush_output()?,
+                    _ => {}
+                }
+            }
+        };
+        // A detached shell keeps its output for the next attach
+        if matches!(end, SessionEnd::Hangup | SessionEnd::InitExited) {
+            self.drain_output()?;
+        }
+        Ok(end)
+    }
+
+    /// Forward a read of input to master, return whether the escape sequence completed
+    ///
+    /// At end of input the shell gets an end of file, as if typed, and input is no longer read.
+    /// `last_activity` is updated whenever something was read.
+    fn forward_input(&mut self, buf: &mut [u8], last_activity: &mut Instant) -> Result<bool> {
+        match read(self.in_fd, buf) {
+            Ok(0) => {
+                self.input = Input::Closed;
+                let c = self.control_char(SpecialCharacterIndices::VEOF, 0x04);
+                self.in_pending.push(c);
+                self.flush_input()?;
+                Ok(false)
+            }
+            Ok(n) => {
+                *last_activity = Instant::now();
+                self.in_pending.extend_from_slice(&buf[..n]);
+                self.flush_input()?;
+                Ok(self.match_escape(&buf[..n]))
+            }
+            // Level triggered, so it is read again on the next wait
+            Err(Errno::EWOULDBLOCK | Errno::EINTR) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Control character `index` of master, `default` if unreadable
+    fn control_char(&self, index: SpecialCharacterIndices, default: u8) -> u8 {
+        termios::tcgetattr(self.master).map_or(default, |t| t.control_chars[index as usize])
+    }
+
+    /// Feed typed `input` to the escape sequence matcher, return whether it completed
+    fn match_escape(&mut self, input: &[u8]) -> bool {
+        let escape = match &self.escape {
+            Some(escape) => escape,
+            None => return false,
+        };
+        for &b in input {
+            if b == escape[self.escape_matched] {
+                self.escape_matched += 1;
+            } else {
+                self.escape_matched = usize::from(b == escape[0]);
+            }
+            if self.escape_matched == escape.len() {
+                self.escape_matched = 0;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Write master output to `out_fd`, keeping what would block for `flush_output`
+    fn forward_output(&mut self, data: &[u8]) -> Result<()> {
+        self.record(data);
+        self.out_pending.extend_from_slice(data);
+        self.flush_output()
+    }
+
+    /// Pass master output `data` to the recorder, if any
+    fn record(&mut self, data: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            // Recording is best effort, it must not end the session
+            if let Err(e) = recorder.output(data) {
+                eprintln!("stop recording: {}\r", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    /// Forward whatever master still holds and all pending output once the session ended
+    ///
+    /// Waits for `out_fd` as long as it takes, so the last words of the shell are not lost.
+    fn drain_output(&mut self) -> Result<()> {
+        let mut buf = vec![0; self.buffer_size];
+        loop {
+            match read(self.master, &mut buf) {
+                Ok(0) | Err(Errno::EWOULDBLOCK | Errno::EIO) => break,
+                Ok(n) => {
+                    self.record(&buf[..n]);
+                    self.out_pending.extend_from_slice(&buf[..n]);
+                }
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        write_all(self.out_fd, &self.out_pending)?;
+        self.out_pending.clear();
+        Ok(())
+    }
+
+    /// Write pending master output as far as `out_fd` takes it
+    ///
+    /// While output is pending, master is not read and `out_fd` is watched for
+    /// writability instead, so a slow reader slows the shell down rather than losing data.
+    fn flush_output(&mut self) -> Result<()> {
+        while !self.out_pending.is_empty() {
+            match write(self.out_fd, &self.out_pending) {
+                Ok(n) => drop(self.out_pending.drain(..n)),
+                Err(Errno::EINTR) => {}
+                Err(Errno::EWOULDBLOCK) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.update_events()
+    }
+
+    /// Write pending input to master as far as it takes it
+    ///
+    /// While input is pending, input is not read and master is watched for writability as
+    /// well, so a shell not reading its input holds the typing back instead of angea.
+    fn flush_input(&mut self) -> Result<()> {
+        while !self.in_pending.is_empty() {
+            match write(self.master, &self.in_pending) {
+                Ok(n) => drop(self.in_pending.drain(..n)),
+                Err(Errno::EINTR) => {}
+                Err(Errno::EWOULDBLOCK) => break,
+                // The shell hung up, which the next read of master tells
+                Err(Errno::EIO) => self.in_pending.clear(),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.update_events()
+    }
+
+    /// Bring the epoll set in line with the pending output and input
+    fn update_events(&mut self) -> Result<()> {
+        let mut master = EpollFlags::empty();
+        if self.out_pending.is_empty() {
+            master |= EpollFlags::EPOLLIN;
+        }
+        if !self.in_pending.is_empty() {
+            master |= EpollFlags::EPOLLOUT;
+        }
+        if master != self.master_events {
+            let op = if self.master_events.is_empty() {
+                EpollOp::EpollCtlAdd
+            } else if master.is_empty() {
+                EpollOp::EpollCtlDel
+            } else {
+                EpollOp::EpollCtlMod
+            };
+            let mut event = EpollEvent::new(master, MASTER_EVENT);
+            epoll::epoll_ctl(self.epoll, op, self.master, &mut event)?;
+            self.master_events = master;
+        }
+
+        let output = !self.out_pending.is_empty();
+        if output != self.output_watched {
+            Self::watch(
+                self.epoll,
+                self.out_fd,
+                output,
+                EpollFlags::EPOLLOUT,
+                OUTPUT_EVENT,
+            )?;
+            self.output_watched = output;
+        }
+
+        let input = self.input == Input::Polled && self.in_pending.is_empty();
+        if input != self.input_watched {
+            Self::watch(
+                self.epoll,
+                self.in_fd,
+                input,
+                EpollFlags::EPOLLIN,
+                STDIN_EVENT,
+            )?;
+            self.input_watched = input;
+        }
+        Ok(())
+    }
+
+    /// Add `fd` to or remove it from `epoll`
+    fn watch(epoll: RawFd, fd: RawFd, add: bool, flags: EpollFlags, data: u64) -> Result<()> {
+        let op = if add {
+            EpollOp::EpollCtlAdd
+        } else {
+            EpollOp::EpollCtlDel
+        };
+        epoll::epoll_ctl(epoll, op, fd, &mut EpollEvent::new(flags, data))?;
+        Ok(())
+    }
+
+    /// Copy the window size of the terminal to master if it changed
+    ///
+    /// Does nothing if neither output, input nor `/dev/tty` is a terminal, or with
+    /// an explicit `PTYForwardBuilder::window_size`.
+    pub fn resize(&mut self) -> Result<()> {
+        let fd = match self.size_fd {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
+        let size = get_winsize(fd)?;
+        if self.last_size != Some((size.ws_row, size.ws_col)) {
+            set_winsize(self.master, &size)?;
+            self.last_size = Some((size.ws_row, size.ws_col));
+        }
+        Ok(())
+    }
+
+    /// Set the window size of master to `rows` x `cols`
+    pub fn set_window_size(&mut self, rows: u16, cols: u16) -> Result<()> {
+        let size = libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        set_winsize(self.master, &size)?;
+        self.last_size = Some((rows, cols));
+        Ok(())
+    }
+
+    /// Recovery termios and non-block status
+    ///
+    /// # Errors
+    ///
+    /// Unexpected I/O error. But it should be no error because `PTYForward::new()` is ok.
+    fn disconnect(&self) -> Result<()> {
+        if let Some(origin) = &self.out_origin {
+            termios::tcsetattr(self.out_fd, SetArg::TCSANOW, origin)?;
+        }
+        if let Some(origin) = &self.in_origin {
+            termios::tcsetattr(self.in_fd, SetArg::TCSANOW, origin)?;
+        }
+        Self::set_nonblock(self.in_fd, false)?;
+        Ok(())
+    }
+
+    /// Set I/O non-block
+    ///
+    /// # Errors
+    ///
+    /// Unexpected I/O error
+    fn set_nonblock(fd: RawFd, nonblock: bool) -> Result<()> {
+        let bits = fcntl(fd, FcntlArg::F_GETFL)?;
+        let mut flags = unsafe { OFlag::from_bits_unchecked(bits) };
+        flags = if nonblock {
+            flags | OFlag::O_NONBLOCK
+        } else {
+            flags & !OFlag::O_NONBLOCK
+        };
+        fcntl(fd, FcntlArg::F_SETFL(flags))?;
+        Ok(())
+    }
+
+    /// Set raw termios config, return origin config for recovery
+    ///
+    /// Returns `None` without touching `fd` if it is not a terminal.
+    fn set_raw_termios(fd: RawFd) -> Result<Option<Termios>> {
+        if !isatty(fd)? {
+            return Ok(None);
+        }
+        let stdin_origin = termios::tcgetattr(fd)?;
+        let mut stdin_attr = stdin_origin.clone();
+        termios::cfmakeraw(&mut stdin_attr);
+        termios::tcsetattr(fd, SetArg::TCSANOW, &stdin_attr)?;
+        Ok(Some(stdin_origin))
+    }
+}
+
+impl Drop for PTYForward {
+    fn drop(&mut self) {
+        if let Err(e) = self.disconnect() {
+            println!("error when disconnecting: {}", e);
+        }
+        for fd in [self.init_fd, self.tty_fd].into_iter().flatten() {
+            let _ = close(fd);
+        }
+        let _ = sigprocmask(SigmaskHow::SIG_SETMASK, Some(&self.sig_origin), None);
+    }
+}
+
+/// Write all of `buf` to `fd`, waiting briefly whenever it would block
+fn write_all(fd: RawFd, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match write(fd, buf) {
+            Ok(n) => buf = &buf[n..],
+            Err(Errno::EINTR) => {}
+            Err(Errno::EWOULDBLOCK) => thread::sleep(Duration::from_millis(1)),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Make `fd` blocking and, if a terminal, put it back in cooked mode like `stty sane`
+///
+/// For terminals left raw by an angea which was killed before it could restore them.
+pub fn reset_terminal(fd: RawFd) -> Result<()> {
+    PTYForward::set_nonblock(fd, false)?;
+    if !isatty(fd)? {
+        return Ok(());
+    }
+    let mut attr = termios::tcgetattr(fd)?;
+    attr.input_flags |= InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::IMAXBEL;
+    attr.input_flags &= !(InputFlags::IGNBRK
+        | InputFlags::INLCR
+        | InputFlags::IGNCR
+        | InputFlags::IXOFF
+        | InputFlags::IXANY);
+    attr.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+    attr.output_flags &= !(OutputFlags::OCRNL | OutputFlags::ONOCR | OutputFlags::ONLRET);
+    attr.control_flags |= ControlFlags::CREAD;
+    attr.local_flags |= LocalFlags::ISIG
+        | LocalFlags::ICANON
+        | LocalFlags::IEXTEN
+        | LocalFlags::ECHO
+        | LocalFlags::ECHOE
+        | LocalFlags::ECHOK
+        | LocalFlags::ECHOCTL
+        | LocalFlags::ECHOKE;
+    attr.local_flags &=
+        !(LocalFlags::ECHONL | LocalFlags::NOFLSH | LocalFlags::TOSTOP | LocalFlags::ECHOPRT);
+    termios::tcsetattr(fd, SetArg::TCSANOW, &attr)?;
+    Ok(())
+}
+
+/// Find the terminal to take the window size from: output, else input, else `/dev/tty`
+///
+/// Returns it along with the fd of `/dev/tty` if that had to be opened.
+fn size_source(in_fd: RawFd, out_fd: RawFd) -> Result<(Option<RawFd>, Option<RawFd>)> {
+    for fd in [out_fd, in_fd] {
+        if isatty(fd)? {
+            return Ok((Some(fd), None));
+        }
+    }
+    let flags = OFlag::O_RDONLY | OFlag::O_NOCTTY | OFlag::O_CLOEXEC;
+    match open("/dev/tty", flags, Mode::empty()) {
+        Ok(fd) => Ok((Some(fd), Some(fd))),
+        // No controlling terminal at all
+        Err(_) => Ok((None, None)),
+    }
+}
+
+/// Get window size of terminal `fd`
+fn get_winsize(fd: RawFd) -> Result<libc::winsize> {
+    unsafe {
+        let mut size: libc::winsize = mem::zeroed();
+        if libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) == -1 {
+            return Err(Errno::last().into());
+        }
+        Ok(size)
+    }
+}
+
+/// Set window size of terminal `fd`
+fn set_winsize(fd: RawFd, size: &libc::winsize) -> Result<()> {
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, size) } == -1 {
+        return Err(Errno::last().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+    use nix::unistd::pipe;
+
+    /// Pty master and its raw slave, so bytes pass both ways unchanged
+    fn raw_pty() -> (RawFd, File) {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let name = ptsname_r(&master).unwrap();
+        let slave = open(
+            name.as_str(),
+            OFlag::O_RDWR | OFlag::O_NOCTTY,
+            Mode::empty(),
+        )
+        .unwrap();
+        let mut attr = termios::tcgetattr(slave).unwrap();
+        termios::cfmakeraw(&mut attr);
+        termios::tcsetattr(slave, SetArg::TCSANOW, &attr).unwrap();
+        (master.into_raw_fd(), unsafe { File::from_raw_fd(slave) })
+    }
+
+    /// Read end and write end of a new pipe
+    fn pipe_files() -> (File, File) {
+        let (r, w) = pipe().unwrap();
+        unsafe { (File::from_raw_fd(r), File::from_raw_fd(w)) }
+    }
+
+    #[test]
+    fn escape_detaches_after_forwarding_input() {
+        let (master, mut slave) = raw_pty();
+        let (in_r, mut in_w) = pipe_files();
+        let (_out_r, out_w) = pipe_files();
+        let mut typed = b"ls\r".to_vec();
+        typed.extend_from_slice(DEFAULT_ESCAPE);
+        in_w.write_all(&typed).unwrap();
+
+        let mut f = PTYForward::builder()
+            .input(in_r.as_raw_fd())
+            .output(out_w.as_raw_fd())
+            .window_size(24, 80)
+            .build(master)
+            .unwrap();
+        assert_eq!(f.wait().unwrap(), SessionEnd::Detached);
+        drop(f);
+
+        // The escape sequence reaches the shell as typed
+        let mut forwarded = vec![0; typed.len()];
+        slave.read_exact(&mut forwarded).unwrap();
+        assert_eq!(forwarded, typed);
+        close(master).unwrap();
+    }
+
+    #[test]
+    fn output_is_drained_byte_exact_on_hangup() {
+        let (master, mut slave) = raw_pty();
+        let (in_r, _in_w) = pipe_files();
+        let (mut out_r, out_w) = pipe_files();
+        // Escape heavy, larger than the pty and pipe buffers, split anywhere by reads
+        let payload: Vec<u8> = b"\x1b[?2004h\x1b[?1000h\x1b[200~paste\x1b[201~\x1b[<0;12;7M"
+            .iter()
+            .copied()
+            .cycle()
+            .take(256 * 1024)
+            .collect();
+
+        let expected = payload.clone();
+        let writer = thread::spawn(move || {
+            slave.write_all(&payload).unwrap();
+            // Closing the only slave hangs master up
+        });
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            out_r.read_to_end(&mut output).unwrap();
+            output
+        });
+
+        let mut f = PTYForward::builder()
+            .input(in_r.as_raw_fd())
+            .output(out_w.as_raw_fd())
+            .buffer_size(1000)
+            .build(master)
+            .unwrap();
+        assert_eq!(f.wait().unwrap(), SessionEnd::Hangup);
+        writer.join().unwrap();
+        drop(f);
+        drop(out_w);
+        assert!(reader.join().unwrap() == expected);
+        close(master).unwrap();
+    }
+
+    #[test]
+    fn input_larger_than_pty_buffers_is_echoed_back() {
+        let (master, mut slave) = raw_pty();
+        let (in_r, mut in_w) = pipe_files();
+        let (mut out_r, out_w) = pipe_files();
+        let payload: Vec<u8> = (0..=255u8).cycle().take(256 * 1024).collect();
+
+        let expected = payload.clone();
+        let typist = thread::spawn(move || {
+            in_w.write_all(&payload).unwrap();
+        });
+        // A shell echoing what it reads, which stalls its reads while its writes block
+        let len = expected.len();
+        let shell = thread::spawn(move || {
+            let mut buf = [0; 4096];
+            let mut left = len;
+            while left > 0 {
+                let n = slave.read(&mut buf[..left.min(4096)]).unwrap();
+                slave.write_all(&buf[..n]).unwrap();
+                left -= n;
+            }
+            // Closing the only slave hangs master up
+        });
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            out_r.read_to_end(&mut output).unwrap();
+            output
+        });
+
+        let mut f = PTYForward::builder()
+            .input(in_r.as_raw_fd())
+            .output(out_w.as_raw_fd())
+            .window_size(24, 80)
+            .build(master)
+            .unwrap();
+        assert_eq!(f.wait().unwrap(), SessionEnd::Hangup);
+        typist.join().unwrap();
+        shell.join().unwrap();
+        drop(f);
+        drop(out_w);
+        assert!(reader.join().unwrap() == expected);
+        close(master).unwrap();
+    }
+
+    #[test]
+    fn session_ends_when_init_exits() {
+        let (master, _slave) = raw_pty();
+        let (in_r, _in_w) = pipe_files();
+        let (_out_r, out_w) = pipe_files();
+        let mut init = std::process::Command::new("sleep")
+            .arg("0.1")
+            .spawn()
+            .unwrap();
+
+        let mut f = PTYForward::builder()
+            .input(in_r.as_raw_fd())
+            .output(out_w.as_raw_fd())
+            .window_size(24, 80)
+            .build(master)
+            .unwrap();
+        f.watch_init(Pid::from_raw(init.id() as i32)).unwrap();
+        assert_eq!(f.wait().unwrap(), SessionEnd::InitExited);
+        drop(f);
+        init.wait().unwrap();
+        close(master).unwrap();
+    }
+
+    #[test]
+    fn resize_copies_terminal_size_to_slave() {
+        let (master, slave) = raw_pty();
+        // The terminal angea runs in, output is taken for the size first
+        let (term_master, term) = raw_pty();
+        let (in_r, _in_w) = pipe_files();
+        let size = |rows, cols| libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        set_winsize(term.as_raw_fd(), &size(30, 100)).unwrap();
+
+        let mut f = PTYForward::builder()
+            .input(in_r.as_raw_fd())
+            .output(term.as_raw_fd())
+            .build(master)
+            .unwrap();
+        let winsize = |fd| {
+            let size = get_winsize(fd).unwrap();
+            (size.ws_row, size.ws_col)
+        };
+        assert_eq!(winsize(slave.as_raw_fd()), (30, 100));
+        set_winsize(term.as_raw_fd(), &size(50, 132)).unwrap();
+        f.resize().unwrap();
+        assert_eq!(winsize(slave.as_raw_fd()), (50, 132));
+        drop(f);
+        close(master).unwrap();
+        close(term_master).unwrap();
+    }
+
+    #[test]
+    fn closed_slave_ends_session_with_hangup() {
+        let (master, slave) = raw_pty();
+        let (in_r, _in_w) = pipe_files();
+        let (_out_r, out_w) = pipe_files();
+
+        let mut f = PTYForward::builder()
+            .input(in_r.as_raw_fd())
+            .output(out_w.as_raw_fd())
+            .window_size(24, 80)
+            .build(master)
+            .unwrap();
+        drop(slave);
+        assert_eq!(f.wait().unwrap(), SessionEnd::Hangup);
+        drop(f);
+        close(master).unwrap();
+    }
+}
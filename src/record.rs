@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{Instant, SystemTime};
+
+use crate::error::Result;
+
+/// File format of a session recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Raw output like script(1)
+    Typescript,
+    /// asciinema cast v2, JSON lines with the time since start
+    Asciicast,
+}
+
+/// Recorder of the output of a session
+pub struct Recorder {
+    file: File,
+    format: Format,
+    start: Instant,
+    /// Trailing bytes of an incomplete UTF-8 character, asciicast only
+    partial: Vec<u8>,
+}
+
+impl Recorder {
+    /// Write the header of `format` to `file`, `size` being rows and columns of the terminal
+    pub fn start(mut file: File, format: Format, size: (u16, u16)) -> Result<Recorder> {
+        let now = SystemTime::now();
+        let header = match format {
+            Format::Typescript => format!("Script started on {}\n", timestamp(now)),
+            Format::Asciicast => format!(
+                "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}}}\n",
+                size.1,
+                size.0,
+                unix_secs(now)
+            ),
+        };
+        file.write_all(header.as_bytes())?;
+        Ok(Recorder {
+            file,
+            format,
+            start: Instant::now(),
+            partial: Vec::new(),
+        })
+    }
+
+    /// Record `data` written by the session
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.format {
+            Format::Typescript => self.file.write_all(data),
+            Format::Asciicast => {
+                self.partial.extend_from_slice(data);
+                let end = self.partial.len() - incomplete_tail(&self.partial);
+                if end == 0 {
+                    return Ok(());
+                }
+                let text = String::from_utf8_lossy(&self.partial[..end]);
+                let event = format!(
+                    "[{:.6}, \"o\", {}]\n",
+                    self.start.elapsed().as_secs_f64(),
+                    json_string(&text)
+                );
+                self.partial.drain(..end);
+                self.file.write_all(event.as_bytes())
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if self.format == Format::Typescript {
+            let footer = format!("\nScript done on {}\n", timestamp(SystemTime::now()));
+            let _ = self.file.write_all(footer.as_bytes());
+        }
+    }
+}
+
+/// Length of the incomplete UTF-8 character at the end of `buf`
+fn incomplete_tail(buf: &[u8]) -> usize {
+    for i in 1..=buf.len().min(3) {
+        let b = buf[buf.len() - i];
+        if b & 0xc0 == 0x80 {
+            // Continuation byte, look further back for the leading one
+            continue;
+        }
+        let width = match b {
+            0xf0.. => 4,
+            0xe0.. => 3,
+            0xc0.. => 2,
+            _ => 1,
+        };
+        return if width > i { i } else { 0 };
+    }
+    0
+}
+
+/// Quote `s` as a JSON string
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Format `time` as UTC `YYYY-MM-DD hh:mm:ss+00:00`
+fn timestamp(time: SystemTime) -> String {
+    let secs = unix_secs(time);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}+00:00",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
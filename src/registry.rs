@@ -0,0 +1,228 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+
+use crate::bus::Bus;
+use crate::error::Result;
+
+/// Directory holding one record per shell or exec session, next to the detach sockets
+const DIR: &str = "/run/angea/sessions";
+
+/// Suffix of record files, telling them from detach sockets and half written records
+const SUFFIX: &str = ".rec";
+
+/// Shell session known to the registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Transient unit running the shell
+    pub unit: String,
+    /// Pty slave path of the shell, `-` for `angea exec` without a pty
+    pub pts: String,
+    pub user: String,
+    /// Unix time in seconds the session started
+    pub started: u64,
+    /// Pid of the angea process forwarding the session
+    pub pid: Pid,
+}
+
+impl Record {
+    /// Record of a session forwarded by this process, started now
+    pub fn new(unit: String, pts: String, user: String) -> Record {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Record {
+            unit,
+            pts,
+            user,
+            started,
+            pid: Pid::this(),
+        }
+    }
+
+    /// Write the record, it is removed again when the returned guard drops
+    pub fn register(self) -> Result<Registration> {
+        self.register_in(Path::new(DIR))
+    }
+
+    fn register_in(self, dir: &Path) -> Result<Registration> {
+        let _lock = lock(dir)?;
+        let path = record_path(dir, &self.unit);
+        let tmp = path.with_extension("tmp");
+        let mut f = File::create(&tmp)?;
+        write!(
+            f,
+            "unit={}\npts={}\nuser={}\nstarted={}\npid={}\n",
+            self.unit, self.pts, self.user, self.started, self.pid
+        )?;
+        fs::rename(&tmp, &path)?;
+        Ok(Registration { path })
+    }
+
+    fn parse(content: &str) -> Option<Record> {
+        let mut record = Record {
+            unit: String::new(),
+            pts: String::new(),
+            user: String::new(),
+            started: 0,
+            pid: Pid::from_raw(0),
+        };
+        for line in content.lines() {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "unit" => record.unit = value.to_string(),
+                "pts" => record.pts = value.to_string(),
+                "user" => record.user = value.to_string(),
+                "started" => record.started = value.parse().ok()?,
+                "pid" => record.pid = Pid::from_raw(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        if record.unit.is_empty() || record.pid.as_raw() <= 0 {
+            return None;
+        }
+        Some(record)
+    }
+}
+
+/// Registered session, its record is removed on drop
+#[derive(Debug)]
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let dir = self.path.parent().unwrap_or(Path::new(DIR));
+        if let Ok(_lock) = lock(dir) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Directory of the registry
+pub fn dir() -> &'static Path {
+    Path::new(DIR)
+}
+
+/// Read all records, skipping malformed ones
+pub fn records() -> Result<Vec<Record>> {
+    records_in(Path::new(DIR))
+}
+
+fn records_in(dir: &Path) -> Result<Vec<Record>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(SUFFIX) {
+            continue;
+        }
+        if let Some(record) = fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| Record::parse(&c))
+        {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// Remove records left by crashed sessions, returning them
+///
+/// A record is stale when its forwarding angea is gone or its unit is no longer active.
+/// Units still active without a forwarding angea are stopped.
+pub fn prune(bus: &Bus) -> Result<Vec<Record>> {
+    let dir = Path::new(DIR);
+    let _lock = lock(dir)?;
+    let active: Vec<String> = bus
+        .list_units(&["angea-shell@*.service", "angea-exec-*.service"])?
+        .into_iter()
+        .filter(|u| u.active_state == "active")
+        .map(|u| u.name)
+        .collect();
+    let mut pruned = Vec::new();
+    for record in records()? {
+        // Not through /proc, which shows another PID namespace after entering systemd's mounts
+        let alive = kill(record.pid, None) != Err(Errno::ESRCH);
+        if !alive || !active.contains(&record.unit) {
+            if active.contains(&record.unit) {
+                // Nobody forwards this shell any more
+                let _ = bus.stop_unit(&record.unit);
+            }
+            fs::remove_file(record_path(dir, &record.unit))?;
+            pruned.push(record);
+        }
+    }
+    Ok(pruned)
+}
+
+/// Path of the record of `unit` in registry `dir`
+fn record_path(dir: &Path, unit: &str) -> PathBuf {
+    dir.join(format!("{}{}", unit, SUFFIX))
+}
+
+/// Take the lock of registry `dir`, released when the returned file is closed
+///
+/// The lock file sits next to `dir`, like `/run/angea/sessions.lock`.
+fn lock(dir: &Path) -> Result<File> {
+    fs::create_dir_all(dir)?;
+    let f = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.with_extension("lock"))?;
+    flock(f.as_raw_fd(), FlockArg::LockExclusive)?;
+    Ok(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    /// Empty registry directory of its own for `name`
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("angea-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn register_round_trips_through_records() {
+        let dir = temp_dir("registry");
+        let record = Record::new(
+            "angea-shell@3.service".to_string(),
+            "/dev/pts/3".to_string(),
+            "root".to_string(),
+        );
+        let registration = record.clone().register_in(&dir).unwrap();
+        // A record left half written is no record
+        File::create(dir.join("angea-shell@4.service.tmp")).unwrap();
+        // Detach sockets share the directory and are no records
+        File::create(dir.join("angea-shell@3.service.sock")).unwrap();
+        assert_eq!(records_in(&dir).unwrap(), vec![record]);
+
+        drop(registration);
+        assert!(records_in(&dir).unwrap().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(dir.with_extension("lock"));
+    }
+
+    #[test]
+    fn records_of_missing_dir_are_empty() {
+        assert!(records_in(&temp_dir("missing")).unwrap().is_empty());
+    }
+}
@@ -1,96 +1,437 @@
 use std::env;
-use std::ffi::c_void;
-use std::mem;
-use std::mem::MaybeUninit;
+use std::ffi::CString;
 use std::os::raw::c_char;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::RawFd;
 use std::os::unix::prelude::IntoRawFd;
 use std::ptr;
+use std::time::{Duration, Instant};
 
 use nix::errno::Errno;
-use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::fcntl::OFlag;
 use nix::pty::{posix_openpt, ptsname_r, unlockpt};
-use nix::sys::epoll::{self, EpollEvent, EpollFlags, EpollOp};
-use nix::sys::signal::{sigprocmask, SigmaskHow, Signal};
-use nix::sys::signalfd::{SigSet, SignalFd};
-use nix::sys::termios::{self, SetArg, Termios};
-use nix::unistd::{read, write};
-use nix::Result;
+use nix::sys::signal::Signal;
+use nix::sys::signalfd::SignalFd;
+use nix::unistd::{access, AccessFlags, Pid};
 
 use libsystemd_sys::bus::*;
 
-/// Init and get pty master fd
-pub fn get_pty(user: String) -> Result<RawFd> {
+use crate::bus::{
+    assert, char, void, Bus, SD_BUS_TYPE_ARRAY, SD_BUS_TYPE_STRUCT, SD_BUS_TYPE_VARIANT,
+};
+use crate::error::{Error, Result};
+
+/// Extra properties of the shell unit
+#[derive(Debug, Default, Clone)]
+pub struct ShellOptions {
+    /// `User` of the shell as name or numeric uid, root when `None`
+    pub user: Option<String>,
+    /// Command line of the shell, `ANGEA_ARGS` split on whitespace or `/bin/bash -l` when empty
+    pub args: Vec<String>,
+    /// `WorkingDirectory` of the shell, the home of the user if it exists when `None`
+    pub working_directory: Option<String>,
+    /// `KEY=VALUE` environment of the shell, see `environment_from_env`
+    pub environment: Vec<String>,
+    /// `EnvironmentFile`s of the unit as `(path, ignore_missing)`, omitted when empty
+    ///
+    /// systemd reads them itself, so paths resolve inside its namespace.
+    pub environment_files: Vec<(String, bool)>,
+    /// `Documentation` URIs of the unit, omitted when empty
+    pub documentation: Vec<String>,
+    /// `AmbientCapabilities` mask, omitted when zero
+    ///
+    /// systemd takes capability sets over D-Bus as a `t` bit mask, bit N standing for capability N.
+    pub ambient_capabilities: u64,
+    /// `CapabilityBoundingSet` mask, omitted when `None`
+    pub capability_bounding_set: Option<u64>,
+    /// How long the start job of the unit may take, 30 seconds when `None`
+    pub start_timeout: Option<Duration>,
+    /// Leave `TTYReset`, `TTYVHangup` and `TTYVTDisallocate` off, which are on by default
+    pub keep_tty_state: bool,
+    /// `Group` of the shell as name or numeric gid, the primary group of the user when `None`
+    pub group: Option<String>,
+    /// `SupplementaryGroups` of the shell as names or numeric gids, omitted when empty
+    pub supplementary_groups: Vec<String>,
+    /// `PAMName` of the unit, the PAM service the shell opens a login session with
+    pub pam_name: Option<String>,
+    /// `Slice` of the unit, systemd's default slice when `None`
+    pub slice: Option<String>,
+    /// `MemoryMax` of the unit in bytes, unlimited when `None`
+    pub memory_max: Option<u64>,
+    /// `CPUQuota` of the unit in percent of one CPU, unlimited when `None`
+    pub cpu_quota: Option<u64>,
+    /// Further unit properties like `IOWeight`, set after all others
+    ///
+    /// Each takes the D-Bus type systemd expects for it, see `org.freedesktop.systemd1(5)`.
+    pub properties: Vec<(String, PropertyValue)>,
+}
+
+/// Value of a unit property, by its D-Bus type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropertyValue {
+    /// `s`
+    Str(String),
+    /// `b`
+    Bool(bool),
+    /// `i`
+    I32(i32),
+    /// `u`
+    U32(u32),
+    /// `x`
+    I64(i64),
+    /// `t`
+    U64(u64),
+    /// `as`
+    StrList(Vec<String>),
+}
+
+impl ShellOptions {
+    /// Options as `angea shell` takes them from its environment, see `environment_from_env`
+    pub fn from_env() -> Result<ShellOptions> {
+        Ok(ShellOptions {
+            environment: environment_from_env()?,
+            ..Default::default()
+        })
+    }
+
+    /// Run the shell as `user`, a name or numeric uid
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Run `args` instead of the default shell
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set variable `key` of the shell environment as by `set_env`
+    pub fn env(mut self, key: &str, value: &str) -> Result<Self> {
+        self.set_env(key, value)?;
+        Ok(self)
+    }
+
+    /// Also read variables from `path` like `EnvironmentFile=`, may be repeated
+    ///
+    /// A missing file fails the start of the shell unless `ignore_missing`.
+    pub fn environment_file(mut self, path: impl Into<String>, ignore_missing: bool) -> Self {
+        self.environment_files.push((path.into(), ignore_missing));
+        self
+    }
+
+    /// Set `TERM` of the shell
+    pub fn term(mut self, term: &str) -> Self {
+        self.environment.retain(|e| !e.starts_with("TERM="));
+        self.environment.push(format!("TERM={}", term));
+        self
+    }
+
+    /// Run the shell with primary group `group`, a name or numeric gid
+    pub fn group(mut self, group: impl Into<String>) -> Self {
+        self.group = Some(group.into());
+        self
+    }
+
+    /// Also run the shell in `group`, like `wheel` for sudo, may be repeated
+    pub fn supplementary_group(mut self, group: impl Into<String>) -> Self {
+        self.supplementary_groups.push(group.into());
+        self
+    }
+
+    /// Open a PAM session with service `name`, like `login`, so logind registers the shell
+    pub fn pam_name(mut self, name: impl Into<String>) -> Self {
+        self.pam_name = Some(name.into());
+        self
+    }
+
+    /// Put the unit into `slice`, like `angea.slice`, to account and limit sessions together
+    pub fn slice(mut self, slice: impl Into<String>) -> Self {
+        self.slice = Some(slice.into());
+        self
+    }
+
+    /// Limit the memory of the shell and all it starts to `bytes`
+    pub fn memory_max(mut self, bytes: u64) -> Self {
+        self.memory_max = Some(bytes);
+        self
+    }
+
+    /// Limit the CPU time of the shell and all it starts to `percent` of one CPU
+    pub fn cpu_quota(mut self, percent: u64) -> Self {
+        self.cpu_quota = Some(percent);
+        self
+    }
+
+    /// Set unit property `name` to `value`, see `properties`
+    pub fn property(mut self, name: &str, value: PropertyValue) -> Self {
+        self.properties.push((name.to_string(), value));
+        self
+    }
+
+    /// Start in `dir` instead of the home of the user, see `working_directory`
+    pub fn working_dir(mut self, dir: impl Into<String>) -> Self {
+        self.working_directory = Some(dir.into());
+        self
+    }
+
+    /// User the shell runs as, see `user`
+    pub(crate) fn user_or_root(&self) -> &str {
+        self.user.as_deref().unwrap_or("root")
+    }
+
+    /// Set variable `key` of the shell environment, replacing an earlier value
+    ///
+    /// Lets library users build the environment without touching their own.
+    /// The later of two values of a variable wins, `environment_from_env` ones included.
+    pub fn set_env(&mut self, key: &str, value: &str) -> Result<()> {
+        if key.is_empty() || key.contains('=') {
+            return Err(Errno::EINVAL.into());
+        }
+        self.environment
+            .retain(|e| e.split('=').next() != Some(key));
+        self.environment.push(format!("{}={}", key, value));
+        Ok(())
+    }
+
+    /// Set each `(key, value)` of `vars` as by `set_env`
+    pub fn extend_env<K, V>(&mut self, vars: impl IntoIterator<Item = (K, V)>) -> Result<()>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        for (key, value) in vars {
+            self.set_env(key.as_ref(), value.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+/// Shell started by `get_pty`
+#[derive(Debug)]
+pub struct Session {
+    master: RawFd,
+    slave: String,
+    unit: String,
+}
+
+impl Session {
+    /// Pty master fd to forward
+    pub fn master(&self) -> RawFd {
+        self.master
+    }
+
+    /// Path of the pty slave the shell runs on, like `/dev/pts/3`
+    ///
+    /// The path stays valid for the life of the session, it may be reused by
+    /// another pty once the master is closed.
+    pub fn slave_path(&self) -> &str {
+        &self.slave
+    }
+
+    /// Name of the transient unit running the shell
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+}
+
+/// Init pty and start a shell on it
+pub fn get_pty(bus: &Bus, opts: &ShellOptions) -> Result<Session> {
     // pty peer
-    let pty = posix_openpt(OFlag::O_NONBLOCK | OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+    // glibc passes the flags on to open(2), so O_CLOEXEC keeps master from children
+    let flags = OFlag::O_NONBLOCK | OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC;
+    let pty = posix_openpt(flags).inspect_err(|&e| {
+        if e == Errno::ENOENT || e == Errno::ENODEV {
+            eprintln!("cannot open /dev/ptmx, devpts must be mounted on /dev/pts");
+        }
+    })?;
     unlockpt(&pty)?;
     let pts = ptsname_r(&pty)?;
+    if access(pts.as_str(), AccessFlags::F_OK).is_err() {
+        eprintln!("{} does not exist, devpts must be mounted on /dev/pts", pts);
+        return Err(Errno::ENOENT.into());
+    }
 
     // dbus method call
-    unsafe { dbus(user, pts)? };
-    window_resize(pty.as_raw_fd())?;
-    Ok(pty.into_raw_fd())
+    let args = if opts.args.is_empty() {
+        env::var("ANGEA_ARGS")
+            .map(|v| v.split_ascii_whitespace().map(String::from).collect())
+            .unwrap_or_else(|_| vec![String::from("/bin/bash"), String::from("-l")])
+    } else {
+        opts.args.clone()
+    };
+    let stdio = Stdio::Tty(pts.clone());
+    unsafe { dbus(bus, &unit_name(&pts), &args, &stdio, opts)? };
+    Ok(Session {
+        master: pty.into_raw_fd(),
+        unit: unit_name(&pts),
+        slave: pts,
+    })
+}
+
+/// Start `command` as the user of `opts` with angea's stdio, returning its unit
+///
+/// Wait for it with `exit_status`.
+pub fn start_exec(bus: &Bus, command: &[String], opts: &ShellOptions) -> Result<String> {
+    let unit = format!("angea-exec-{}.service", Pid::this());
+    unsafe { dbus(bus, &unit, command, &Stdio::Inherit, opts)? };
+    Ok(unit)
+}
+
+/// Wait for the main process of started `unit` to exit and return its exit status
+///
+/// A process killed by a signal returns 128 plus the signal number, like shells do.
+/// A unit which was stopped or unloaded meanwhile counts as finished, with status 1
+/// if it is gone before its status could be read. Fails with `Error::Timeout` if the
+/// process has not exited within `timeout`. The unit is stopped afterwards.
+///
+/// Signals read from `forward` meanwhile are sent on to the processes of the unit,
+/// which exit or not like they would in the foreground.
+pub fn exit_status(
+    bus: &Bus,
+    unit: &str,
+    timeout: Option<Duration>,
+    mut forward: Option<&mut SignalFd>,
+) -> Result<i32> {
+    let path = match bus.unit_path(unit) {
+        Ok(path) => path,
+        Err(e) if is_unit_gone(&e) => return Ok(1),
+        Err(e) => return Err(e),
+    };
+    let mut watch = bus.watch_unit(&path)?;
+    let start = Instant::now();
+    let finished = loop {
+        match main_status(bus, &path) {
+            Ok(Some(finished)) => break Some(finished),
+            Ok(None) => {}
+            Err(e) if is_unit_gone(&e) => break None,
+            Err(e) => return Err(e),
+        }
+        let mut interval = UNIT_POLL_INTERVAL;
+        if let Some(signal_fd) = forward.as_deref_mut() {
+            while let Some(info) = signal_fd.read_signal()? {
+                if let Ok(signal) = Signal::try_from(info.ssi_signo as i32) {
+                    bus.kill_unit(unit, signal)?;
+                }
+            }
+            interval = SIGNAL_POLL_INTERVAL;
+        }
+        let left = match timeout {
+            Some(timeout) => timeout
+                .checked_sub(start.elapsed())
+                .ok_or(Error::Timeout("the shell to exit"))?,
+            None => interval,
+        };
+        // Signals are only missed if systemd drops them, so still look now and then
+        watch.wait(left.min(interval))?;
+    };
+    drop(watch);
+    let (status, failed) = match finished {
+        Some(finished) => finished,
+        None => return Ok(1),
+    };
+    let ret = if failed {
+        bus.reset_failed_unit(unit)
+    } else {
+        bus.stop_unit(unit)
+    };
+    match ret {
+        Err(e) if !is_unit_gone(&e) => Err(e),
+        _ => Ok(status),
+    }
 }
 
-const SD_BUS_TYPE_ARRAY: c_char = 'a' as c_char;
+/// Exit status of the main process of unit `path` and whether the unit failed, once it exited
+fn main_status(bus: &Bus, path: &str) -> Result<Option<(i32, bool)>> {
+    let active = bus.unit_property_str(path, UNIT_INTERFACE, "ActiveState\0")?;
+    let sub = bus.unit_property_str(path, UNIT_INTERFACE, "SubState\0")?;
+    let failed = active == "failed";
+    // `exited` on its own, `dead` once stopped
+    if !failed && sub != "exited" && active != "inactive" {
+        return Ok(None);
+    }
+    let code = bus.unit_property_i32(path, SERVICE_INTERFACE, "ExecMainCode\0")?;
+    let status = bus.unit_property_i32(path, SERVICE_INTERFACE, "ExecMainStatus\0")?;
+    let status = if code == libc::CLD_EXITED {
+        status
+    } else {
+        128 + status
+    };
+    Ok(Some((status, failed)))
+}
+
+/// Whether `e` says the unit is no longer loaded
+fn is_unit_gone(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::Dbus { name, .. } if name == "org.freedesktop.systemd1.NoSuchUnit"
+            || name == "org.freedesktop.DBus.Error.UnknownObject"
+    )
+}
+
+/// How long a session may take to exit after its terminal hung up
+pub const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait for a unit property change before reading the properties again
+const UNIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-const SD_BUS_TYPE_VARIANT: c_char = 'v' as c_char;
+/// Longest wait for a unit before looking for signals to forward to it
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-const SD_BUS_TYPE_STRUCT: c_char = 'r' as c_char;
+const UNIT_INTERFACE: &str = "org.freedesktop.systemd1.Unit\0";
+
+const SERVICE_INTERFACE: &str = "org.freedesktop.systemd1.Service\0";
+
+/// Standard I/O of a started unit
+enum Stdio {
+    /// Terminal at this pty slave path
+    Tty(String),
+    /// Duplicates of angea's own stdin, stdout and stderr
+    Inherit,
+}
+
+/// How long the start job of the shell unit may take by default
+const START_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[rustfmt::skip]
-/// D-Bus call to spawn a shell service in systemd
-unsafe fn dbus(user: String, slave: String) -> Result<()> {
+/// D-Bus call to spawn `args` as service `unit` in systemd
+unsafe fn dbus(
+    bus: &Bus,
+    unit: &str,
+    args: &[String],
+    stdio: &Stdio,
+    opts: &ShellOptions,
+) -> Result<()> {
     // Arguments
-    let mut args = env::var("ANGEA_ARGS")
-        .map(|v| v.split_ascii_whitespace().map(append_null_ref).collect())
-        .unwrap_or_else(|_| vec!["/bin/bash\0".to_string(), "-l\0".to_string()]);
-    let mut args: Vec<*mut c_char> = args
-        .iter_mut()
-        .map(|s| s.as_mut_ptr().cast())
-        .chain(Some(ptr::null_mut()))
-        .collect();
+    let args = c_strings(args)?;
+    let mut args = strv(&args);
 
     // Environment Variables
-    let mut envs: Vec<String> = Vec::new();
-    if let Ok(s) = env::var("ANGEA_ENVS") {
-        envs.extend(s.split(',').map(|s| append_null_ref(s.trim())));
-    }
-    if let Ok(s) = env::var("TERM") {
-        envs.push(format!("TERM={}\0", s));
-    }
-    if let Ok(s) = env::var("ANGEA_ENV_INHERIT") {
-        envs.extend(
-            s.split(',')
-                .filter_map(|k| env::var(k.trim()).map(|v| format!("{}={}\0", k, v)).ok())
-        );
-    }
-    let mut envs: Vec<*mut c_char> = envs
-        .iter_mut()
-        .map(|s| s.as_mut_ptr().cast())
-        .chain(Some(ptr::null_mut()))
-        .collect();
+    let envs = c_strings(&opts.environment)?;
+    let mut envs = strv(&envs);
 
-        let pts_id = slave.trim_start_matches("/dev/pts/");
-        let service = format!("angea-shell@{}.service\0", pts_id);
-        let slave = append_null_owned(slave);
-        let user = append_null_owned(user);
-
-        // Init bus and message
-        let mut bus = MaybeUninit::uninit();
-        assert(sd_bus_default_system(bus.as_mut_ptr()))?;
-        let bus = bus.assume_init();
-
-        let mut message = MaybeUninit::uninit();
-        assert(sd_bus_message_new_method_call(
-            bus,
-            message.as_mut_ptr(),
-            char("org.freedesktop.systemd1\0"),
-            char("/org/freedesktop/systemd1\0"),
-            char("org.freedesktop.systemd1.Manager\0"),
-            char("StartTransientUnit\0"),
-        ))?;
-        let message = message.assume_init();
+    // Environment Files
+    let env_files = opts
+        .environment_files
+        .iter()
+        .map(|(path, ignore_missing)| Ok((c_string(path)?, *ignore_missing as i32)))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Documentation
+    let docs = c_strings(&opts.documentation)?;
+    let mut docs = strv(&docs);
+
+        let service = c_string(unit)?;
+        let user = c_string(opts.user_or_root())?;
+        // `-` ignores a missing directory, `~` is the home of the user
+        let working_directory = c_string(opts.working_directory.as_deref().unwrap_or("-~"))?;
+
+        // Init message
+        let call = bus.manager_call("StartTransientUnit\0")?;
+        let message = call.as_ptr();
 
         // Append message arguments
         assert(sd_bus_message_append(message, char("ss\0"), void(&service), void("fail\0")))?;
@@ -99,15 +440,50 @@ unsafe fn dbus(user: String, slave: String) -> Result<()> {
         assert(sd_bus_message_open_container(message, SD_BUS_TYPE_ARRAY, char("(sv)\0")))?;
         assert(sd_bus_message_append(
             message,
-            char("(sv)(sv)(sv)(sv)(sv)(sv)(sv)\0"),
+            char("(sv)(sv)(sv)\0"),
             void("Description\0"),      void("s\0"), void("Angea Shell Serivice\0"),
-            void("WorkingDirectory\0"), void("s\0"), void("~\0"),
-            void("StandardOutput\0"),   void("s\0"), void("tty\0"),
-            void("StandardInput\0"),    void("s\0"), void("tty\0"),
-            void("StandardError\0"),    void("s\0"), void("tty\0"),
-            void("TTYPath\0"),          void("s\0"), void(&slave),
+            void("WorkingDirectory\0"), void("s\0"), void(&working_directory),
             void("User\0"),             void("s\0"), void(&user),
         ))?;
+        // Stay loaded after exit so the exit status can be read, see `exit_status`
+        assert(sd_bus_message_append(message, char("(sv)\0"), void("RemainAfterExit\0"), void("b\0"), 1i32))?;
+        // Fail the start job on a bad user, directory or program instead of after it
+        assert(sd_bus_message_append(message, char("(sv)\0"), void("Type\0"), void("s\0"), void("exec\0")))?;
+
+        // Standard I/O
+        match stdio {
+            Stdio::Tty(slave) => {
+                let slave = c_string(slave)?;
+                assert(sd_bus_message_append(
+                    message,
+                    char("(sv)(sv)(sv)(sv)\0"),
+                    void("StandardOutput\0"), void("s\0"), void("tty\0"),
+                    void("StandardInput\0"),  void("s\0"), void("tty\0"),
+                    void("StandardError\0"),  void("s\0"), void("tty\0"),
+                    void("TTYPath\0"),        void("s\0"), void(&slave),
+                ))?;
+                // Undo what an earlier session on the same pty left behind
+                if !opts.keep_tty_state {
+                    assert(sd_bus_message_append(
+                        message,
+                        char("(sv)(sv)(sv)\0"),
+                        void("TTYReset\0"),         void("b\0"), 1i32,
+                        void("TTYVHangup\0"),       void("b\0"), 1i32,
+                        void("TTYVTDisallocate\0"), void("b\0"), 1i32,
+                    ))?;
+                }
+            }
+            Stdio::Inherit => {
+                // systemd receives duplicates of our stdio
+                assert(sd_bus_message_append(
+                    message,
+                    char("(sv)(sv)(sv)\0"),
+                    void("StandardInputFileDescriptor\0"),  void("h\0"), libc::STDIN_FILENO,
+                    void("StandardOutputFileDescriptor\0"), void("h\0"), libc::STDOUT_FILENO,
+                    void("StandardErrorFileDescriptor\0"),  void("h\0"), libc::STDERR_FILENO,
+                ))?;
+            }
+        }
 
         // Environment
         assert(sd_bus_message_open_container(message, SD_BUS_TYPE_STRUCT, char("sv\0")))?;
@@ -117,6 +493,81 @@ unsafe fn dbus(user: String, slave: String) -> Result<()> {
         assert(sd_bus_message_close_container(message))?;
         assert(sd_bus_message_close_container(message))?;
 
+        // Environment Files
+        if !env_files.is_empty() {
+            assert(sd_bus_message_open_container(message, SD_BUS_TYPE_STRUCT, char("sv\0")))?;
+            assert(sd_bus_message_append(message, char("s\0"), void("EnvironmentFiles\0")))?;
+            assert(sd_bus_message_open_container(message, SD_BUS_TYPE_VARIANT, char("a(sb)\0")))?;
+            assert(sd_bus_message_open_container(message, SD_BUS_TYPE_ARRAY, char("(sb)\0")))?;
+            for (path, ignore_missing) in &env_files {
+                assert(sd_bus_message_append(message, char("(sb)\0"), void(path), *ignore_missing))?;
+            }
+            assert(sd_bus_message_close_container(message))?;
+            assert(sd_bus_message_close_container(message))?;
+            assert(sd_bus_message_close_container(message))?;
+        }
+
+        // Documentation
+        if !opts.documentation.is_empty() {
+            assert(sd_bus_message_open_container(message, SD_BUS_TYPE_STRUCT, char("sv\0")))?;
+            assert(sd_bus_message_append(message, char("s\0"), void("Documentation\0")))?;
+            assert(sd_bus_message_open_container(message, SD_BUS_TYPE_VARIANT, char("as\0")))?;
+            assert(sd_bus_message_append_strv(message, docs.as_mut_ptr()))?;
+            assert(sd_bus_message_close_container(message))?;
+            assert(sd_bus_message_close_container(message))?;
+        }
+
+        // Capabilities
+        if opts.ambient_capabilities != 0 {
+            assert(sd_bus_message_append(
+                message,
+                char("(sv)\0"),
+                void("AmbientCapabilities\0"), void("t\0"), opts.ambient_capabilities,
+            ))?;
+        }
+        if let Some(set) = opts.capability_bounding_set {
+            assert(sd_bus_message_append(
+                message,
+                char("(sv)\0"),
+                void("CapabilityBoundingSet\0"), void("t\0"), set,
+            ))?;
+        }
+
+        // Groups
+        if let Some(group) = &opts.group {
+            append_property(message, "Group", &PropertyValue::Str(group.clone()))?;
+        }
+
+        if !opts.supplementary_groups.is_empty() {
+            let groups = PropertyValue::StrList(opts.supplementary_groups.clone());
+            append_property(message, "SupplementaryGroups", &groups)?;
+        }
+
+        // PAM
+        if let Some(name) = &opts.pam_name {
+            append_property(message, "PAMName", &PropertyValue::Str(name.clone()))?;
+        }
+
+        // Slice
+        if let Some(slice) = &opts.slice {
+            append_property(message, "Slice", &PropertyValue::Str(slice.clone()))?;
+        }
+
+        // Resource limits
+        if let Some(max) = opts.memory_max {
+            append_property(message, "MemoryMax", &PropertyValue::U64(max))?;
+        }
+        if let Some(percent) = opts.cpu_quota {
+            // CPUQuota= is CPUQuotaPerSecUSec on the bus, 1% being 10ms per second
+            let usec = percent.saturating_mul(10_000);
+            append_property(message, "CPUQuotaPerSecUSec", &PropertyValue::U64(usec))?;
+        }
+
+        // Extra properties
+        for (name, value) in &opts.properties {
+            append_property(message, name, value)?;
+        }
+
         // ExecStart
         assert(sd_bus_message_open_container(message, SD_BUS_TYPE_STRUCT, char("sv\0")))?;
         assert(sd_bus_message_append(message, char("s\0"), void("ExecStart\0")))?;
@@ -136,233 +587,400 @@ unsafe fn dbus(user: String, slave: String) -> Result<()> {
         // Auxiliary
         assert(sd_bus_message_append(message, char("a(sa(sv))\0"), 0))?;
 
-        // Send message
-        assert(sd_bus_call(bus, message, 0, ptr::null_mut(), ptr::null_mut()))?;
+        // Send message, then wait for the start job
+        let mut jobs = bus.watch_jobs()?;
+        let reply = bus.call(&call)?;
+        match jobs.wait(&reply, opts.start_timeout.unwrap_or(START_TIMEOUT))?.as_str() {
+            "done" => Ok(()),
+            "canceled" => Err(Errno::ECANCELED.into()),
+            "timeout" => Err(Error::Timeout("the shell unit to start")),
+            result => {
+                match failure_reason(bus, unit) {
+                    Ok(reason) => eprintln!("failed to start {}: {}", unit, reason),
+                    Err(_) => eprintln!("failed to start {}: {}", unit, result),
+                }
+                // Free the name for the next shell on this pty
+                let _ = bus.reset_failed_unit(unit);
+                Err(Errno::EIO.into())
+            }
+        }
+}
 
-        // Free pointer resource
-        sd_bus_close(bus);
-        sd_bus_unref(bus);
-        sd_bus_message_unref(message);
+/// Append unit property `name` set to `value` as `(sv)` to `message`
+unsafe fn append_property(
+    message: *mut sd_bus_message,
+    name: &str,
+    value: &PropertyValue,
+) -> Result<()> {
+    let name = c_string(name)?;
+    assert(sd_bus_message_open_container(
+        message,
+        SD_BUS_TYPE_STRUCT,
+        char("sv\0"),
+    ))?;
+    assert(sd_bus_message_append(message, char("s\0"), name.as_ptr()))?;
+    match value {
+        PropertyValue::Str(s) => {
+            let s = c_string(s)?;
+            assert(sd_bus_message_append(
+                message,
+                char("v\0"),
+                char("s\0"),
+                s.as_ptr(),
+            ))?;
+        }
+        PropertyValue::Bool(b) => {
+            assert(sd_bus_message_append(
+                message,
+                char("v\0"),
+                char("b\0"),
+                *b as i32,
+            ))?;
+        }
+        PropertyValue::I32(n) => {
+            assert(sd_bus_message_append(message, char("v\0"), char("i\0"), *n))?;
+        }
+        PropertyValue::U32(n) => {
+            assert(sd_bus_message_append(message, char("v\0"), char("u\0"), *n))?;
+        }
+        PropertyValue::I64(n) => {
+            assert(sd_bus_message_append(message, char("v\0"), char("x\0"), *n))?;
+        }
+        PropertyValue::U64(n) => {
+            assert(sd_bus_message_append(message, char("v\0"), char("t\0"), *n))?;
+        }
+        PropertyValue::StrList(list) => {
+            let list = c_strings(list)?;
+            let mut ptrs = strv(&list);
+            assert(sd_bus_message_open_container(
+                message,
+                SD_BUS_TYPE_VARIANT,
+                char("as\0"),
+            ))?;
+            assert(sd_bus_message_append_strv(message, ptrs.as_mut_ptr()))?;
+            assert(sd_bus_message_close_container(message))?;
+        }
+    }
+    assert(sd_bus_message_close_container(message))
+}
 
-        Ok(())
+/// Explain why `unit` failed, from its service result and main exit status
+fn failure_reason(bus: &Bus, unit: &str) -> Result<String> {
+    let path = bus.unit_path(unit)?;
+    let result = bus.unit_property_str(&path, SERVICE_INTERFACE, "Result\0")?;
+    let status = bus.unit_property_i32(&path, SERVICE_INTERFACE, "ExecMainStatus\0")?;
+    // Exit statuses systemd uses for a failed setup of the process, see systemd.exec(5)
+    let step = match status {
+        200 => "CHDIR",
+        203 => "EXEC",
+        216 => "GROUP",
+        217 => "USER",
+        218 => "CAPABILITIES",
+        224 => "PAM",
+        226 => "NAMESPACE",
+        _ => return Ok(format!("{}, status {}", result, status)),
+    };
+    Ok(format!("{}, status {}/{}", result, status, step))
 }
 
-/// Convert sd_bus_* return value to `nix::Result`
-fn assert(v: i32) -> Result<()> {
-    if v < 0 {
-        return Err(Errno::from_i32(-v));
+/// Whether `s` is a user or group name, or a numeric id, systemd takes for `User`/`Group`
+///
+/// Numeric ids need no passwd or group entry. (uid_t)-1 and 65535 are not valid ids.
+pub fn is_user_or_id(s: &str) -> bool {
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        return s
+            .parse::<u32>()
+            .is_ok_and(|id| id != u32::MAX && id != 65535);
     }
-    Ok(())
+    let mut bytes = s.bytes();
+    bytes
+        .next()
+        .is_some_and(|b| b.is_ascii_alphabetic() || b == b'_')
+        && bytes.all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+        && s.len() <= 256
 }
 
-/// Convert to `*const c_char`
-fn char<T: StrPtrCast>(v: T) -> *const c_char {
-    v.as_char_ptr()
+/// Shell unit as listed by `list_shells`
+#[derive(Debug, Clone)]
+pub struct ShellUnit {
+    pub unit: String,
+    /// Number of the pty, like `3` for `/dev/pts/3`
+    pub pts: String,
+    pub user: String,
+    /// `ActiveState/SubState`, like `active/running`
+    pub state: String,
+    /// Pid of the shell in the namespace of systemd, 0 once it exited
+    pub main_pid: u32,
 }
 
-/// Convert to `*const c_void`
-fn void<T: StrPtrCast>(v: T) -> *const c_void {
-    v.as_void_ptr()
+/// List the loaded `angea-shell@` units
+pub fn list_shells(bus: &Bus) -> Result<Vec<ShellUnit>> {
+    let mut shells = Vec::new();
+    for unit in bus.list_units(&["angea-shell@*.service"])? {
+        // Units may go away while listing
+        let path = match bus.unit_path(&unit.name) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        let user = bus
+            .unit_property_str(&path, SERVICE_INTERFACE, "User\0")
+            .unwrap_or_default();
+        let main_pid = bus
+            .unit_property_u32(&path, SERVICE_INTERFACE, "MainPID\0")
+            .unwrap_or(0);
+        let pts = unit
+            .name
+            .trim_start_matches("angea-shell@")
+            .trim_end_matches(".service")
+            .to_string();
+        shells.push(ShellUnit {
+            state: format!("{}/{}", unit.active_state, unit.sub_state),
+            unit: unit.name,
+            pts,
+            user,
+            main_pid,
+        });
+    }
+    Ok(shells)
 }
 
-trait StrPtrCast: Sized {
-    fn as_char_ptr(&self) -> *const c_char;
+/// Name of the shell unit on pty slave `pts`
+pub(crate) fn unit_name(pts: &str) -> String {
+    format!(
+        "angea-shell@{}.service",
+        pts.trim_start_matches("/dev/pts/")
+    )
+}
 
-    fn as_void_ptr(&self) -> *const c_void {
-        self.as_char_ptr().cast()
-    }
+/// Check a `Documentation` URI uses a scheme systemd accepts
+pub fn is_documentation_uri(uri: &str) -> bool {
+    ["http://", "https://", "file:", "info:", "man:"]
+        .iter()
+        .any(|scheme| uri.len() > scheme.len() && uri.starts_with(scheme))
 }
 
-impl StrPtrCast for &String {
-    fn as_char_ptr(&self) -> *const c_char {
-        self.as_ptr().cast()
+/// Shell environment configured by angea's own environment
+///
+/// `ANGEA_ENVS`, then `TERM`, then variables named by `ANGEA_ENV_INHERIT`,
+/// all filtered by `ANGEA_ENV_ALLOW` if set.
+/// Fails listing the `ANGEA_ENVS` entries which are not `KEY=VALUE`.
+pub fn environment_from_env() -> Result<Vec<String>> {
+    let mut envs: Vec<String> = Vec::new();
+    if let Ok(s) = env::var("ANGEA_ENVS") {
+        envs = parse_envs("ANGEA_ENVS", &s)?;
     }
+    if let Ok(s) = env::var("TERM") {
+        envs.push(format!("TERM={}", s));
+    }
+    if let Ok(s) = env::var("ANGEA_ENV_INHERIT") {
+        envs.extend(
+            split_names(&s).filter_map(|k| env::var(k).map(|v| format!("{}={}", k, v)).ok()),
+        );
+    }
+    if let Ok(s) = env::var("ANGEA_ENV_ALLOW") {
+        retain_allowed(&mut envs, &s);
+    }
+    Ok(envs)
 }
 
-impl StrPtrCast for &str {
-    fn as_char_ptr(&self) -> *const c_char {
-        self.as_ptr().cast()
+/// Split the comma separated `KEY=VALUE` entries of the variable `name`
+///
+/// Empty entries are skipped, a trailing comma is no typo.
+/// Fails listing the entries without a key.
+pub(crate) fn parse_envs(name: &str, s: &str) -> Result<Vec<String>> {
+    let entries: Vec<&str> = s
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .collect();
+    let bad: Vec<&str> = entries
+        .iter()
+        .copied()
+        .filter(|e| e.split_once('=').is_none_or(|(key, _)| key.is_empty()))
+        .collect();
+    if !bad.is_empty() {
+        eprintln!("{} entries are not KEY=VALUE: {}", name, bad.join(", "));
+        return Err(Errno::EINVAL.into());
     }
+    Ok(entries.into_iter().map(String::from).collect())
 }
 
-impl StrPtrCast for *mut c_char {
-    fn as_char_ptr(&self) -> *const c_char {
-        *self
-    }
+/// Drop `KEY=VALUE` entries whose key is not in the `allow` list
+fn retain_allowed(envs: &mut Vec<String>, allow: &str) {
+    let allow: Vec<&str> = split_names(allow).collect();
+    envs.retain(|e| {
+        let key = e.split('=').next().unwrap_or_default();
+        let allowed = allow.contains(&key);
+        if !allowed {
+            eprintln!("environment variable {} is not allowed, dropped", key);
+        }
+        allowed
+    });
 }
 
-pub struct PTYForward {
-    epoll: RawFd,
-    master: RawFd,
-    signal_fd: SignalFd,
-    stdin_origin: Termios,
-    stdout_origin: Termios,
+/// Split a list of names separated by commas and/or whitespace
+fn split_names(s: &str) -> impl Iterator<Item = &str> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|k| !k.is_empty())
 }
 
-const STDIN_EVENT: u64 = 1;
-
-const MASTER_EVENT: u64 = 2;
-
-const SIGNAL_EVENT: u64 = 3;
-
-impl PTYForward {
-    pub fn new(master: RawFd) -> Result<PTYForward> {
-        let epoll = epoll::epoll_create()?;
-
-        let mut stdin_event = EpollEvent::new(EpollFlags::EPOLLIN, STDIN_EVENT);
-        let mut master_event = EpollEvent::new(EpollFlags::EPOLLIN, MASTER_EVENT);
-        epoll::epoll_ctl(
-            epoll,
-            EpollOp::EpollCtlAdd,
-            libc::STDIN_FILENO,
-            &mut stdin_event,
-        )?;
-        epoll::epoll_ctl(epoll, EpollOp::EpollCtlAdd, master, &mut master_event)?;
-
-        let mut sig_set = SigSet::empty();
-        sig_set.add(Signal::SIGWINCH);
-        sigprocmask(SigmaskHow::SIG_SETMASK, Some(&sig_set), None)?;
-        let signal_fd = SignalFd::new(&sig_set)?;
-        let mut sig_event = EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL_EVENT);
-        epoll::epoll_ctl(
-            epoll,
-            EpollOp::EpollCtlAdd,
-            signal_fd.as_raw_fd(),
-            &mut sig_event,
-        )?;
-
-        Self::set_nonblock(libc::STDIN_FILENO, true)?;
-        let stdin_origin = Self::set_raw_termios(libc::STDIN_FILENO)?;
-        let stdout_origin = Self::set_raw_termios(libc::STDOUT_FILENO)?;
-        Ok(PTYForward {
-            epoll,
-            master,
-            signal_fd,
-            stdin_origin,
-            stdout_origin,
-        })
+/// `s` as a C string, rejecting an embedded NUL which C would silently cut `s` at
+fn c_string(s: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| {
+        eprintln!("embedded NUL in {:?}", s);
+        Error::Sys(Errno::EINVAL)
+    })
+}
+
+/// Each of `strs` as a C string, see `c_string`
+fn c_strings(strs: &[String]) -> Result<Vec<CString>> {
+    strs.iter().map(|s| c_string(s)).collect()
+}
+
+/// NULL terminated array of pointers into `strs`, valid as long as `strs`
+fn strv(strs: &[CString]) -> Vec<*mut c_char> {
+    strs.iter()
+        .map(|s| s.as_ptr() as *mut c_char)
+        .chain(Some(ptr::null_mut()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envs(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| e.to_string()).collect()
     }
 
-    pub fn wait(&mut self) -> Result<()> {
-        wait_service(self.master)?;
-
-        let mut events = [EpollEvent::empty(); 128];
-        let mut buf = [0; 1024];
-        unsafe {
-            'epoll: loop {
-                let n = epoll::epoll_wait(self.epoll, &mut events, -1)?;
-                let ready = events.get_unchecked(..n);
-
-                for ev in ready {
-                    match ev.data() {
-                        STDIN_EVENT => {
-                            // stdin => master
-                            match read(libc::STDIN_FILENO, &mut buf) {
-                                Ok(n) => write(self.master, buf.get_unchecked(..n))?,
-                                Err(Errno::EWOULDBLOCK) => continue,
-                                Err(e) => return Err(e),
-                            };
-                        }
-                        MASTER_EVENT => {
-                            // master => stdout
-                            match read(self.master, &mut buf) {
-                                Ok(n) => write(libc::STDOUT_FILENO, buf.get_unchecked(..n))?,
-                                Err(Errno::EWOULDBLOCK) => continue,
-                                Err(Errno::EIO) => break 'epoll,
-                                Err(e) => return Err(e),
-                            };
-                        }
-                        SIGNAL_EVENT => {
-                            // signal
-                            self.signal_fd.read_signal()?;
-                            window_resize(self.master)?;
-                        }
-                        _ => {}
-                    }
-                }
-            }
+    #[test]
+    fn parse_envs_takes_key_value_entries() {
+        assert!(parse_envs("T", "").unwrap().is_empty());
+        assert!(parse_envs("T", " , ,").unwrap().is_empty());
+        assert_eq!(
+            parse_envs("T", "A=1,, B = 2 ,C=,D=x=y,").unwrap(),
+            envs(&["A=1", "B = 2", "C=", "D=x=y"])
+        );
+    }
+
+    #[test]
+    fn parse_envs_rejects_malformed_entries() {
+        for s in ["=VALUE", "A=1,KEY", "A=1,="] {
+            assert!(parse_envs("T", s).is_err(), "{:?}", s);
         }
-        Ok(())
+        // An embedded NUL passes as KEY=VALUE, but never reaches sd-bus
+        let envs = parse_envs("T", "A=1\0B").unwrap();
+        assert!(c_strings(&envs).is_err());
     }
 
-    /// Recovery termios and non-block status
-    ///
-    /// # Errors
-    ///
-    /// Unexpected I/O error. But it should be no error because `PTYForward::new()` is ok.
-    fn disconnect(&self) -> Result<()> {
-        termios::tcsetattr(libc::STDOUT_FILENO, SetArg::TCSANOW, &self.stdout_origin)?;
-        termios::tcsetattr(libc::STDIN_FILENO, SetArg::TCSANOW, &self.stdin_origin)?;
-        Self::set_nonblock(libc::STDIN_FILENO, false)?;
-        Ok(())
+    #[test]
+    fn split_names_skips_empty_names() {
+        assert_eq!(split_names("").count(), 0);
+        assert_eq!(split_names(" ,\t, ").count(), 0);
+        assert_eq!(
+            split_names("LANG,, TZ \t\nPATH,").collect::<Vec<_>>(),
+            ["LANG", "TZ", "PATH"]
+        );
     }
 
-    /// Set I/O non-block
-    ///
-    /// # Errors
-    ///
-    /// Unexpected I/O error
-    fn set_nonblock(fd: RawFd, nonblock: bool) -> Result<()> {
-        let bits = fcntl(fd, FcntlArg::F_GETFL)?;
-        let mut flags = unsafe { OFlag::from_bits_unchecked(bits) };
-        flags = if nonblock {
-            flags | OFlag::O_NONBLOCK
-        } else {
-            flags & !OFlag::O_NONBLOCK
-        };
-        fcntl(fd, FcntlArg::F_SETFL(flags))?;
-        Ok(())
+    #[test]
+    fn retain_allowed_keeps_only_listed_keys() {
+        let mut e = envs(&["LANG=C", "TZ=UTC", "LD_PRELOAD=x.so", "TERM=xterm"]);
+        retain_allowed(&mut e, "LANG,,TZ  TERM");
+        assert_eq!(e, envs(&["LANG=C", "TZ=UTC", "TERM=xterm"]));
+
+        // Keys match whole, not by prefix
+        let mut e = envs(&["LANGUAGE=en", "LANG=C"]);
+        retain_allowed(&mut e, "LANG");
+        assert_eq!(e, envs(&["LANG=C"]));
     }
 
-    /// Set raw termios config, return origin config for recovery
-    fn set_raw_termios(fd: RawFd) -> Result<Termios> {
-        let stdin_origin = termios::tcgetattr(fd)?;
-        let mut stdin_attr = stdin_origin.clone();
-        termios::cfmakeraw(&mut stdin_attr);
-        termios::tcsetattr(fd, SetArg::TCSANOW, &stdin_attr)?;
-        Ok(stdin_origin)
+    #[test]
+    fn retain_allowed_with_empty_list_drops_all() {
+        let mut e = envs(&["LANG=C", "TZ=UTC"]);
+        retain_allowed(&mut e, " , ");
+        assert!(e.is_empty());
     }
-}
 
-impl Drop for PTYForward {
-    fn drop(&mut self) {
-        if let Err(e) = self.disconnect() {
-            println!("error when disconnecting: {}", e);
-        }
+    #[test]
+    fn builder_sets_options() {
+        let opts = ShellOptions::default()
+            .user("alice")
+            .args(["/bin/sh", "-l"])
+            .env("EDITOR", "vi")
+            .unwrap()
+            .working_dir("/srv")
+            .term("xterm-256color");
+        assert_eq!(opts.user_or_root(), "alice");
+        assert_eq!(opts.args, ["/bin/sh", "-l"]);
+        assert_eq!(opts.environment, ["EDITOR=vi", "TERM=xterm-256color"]);
+        assert_eq!(opts.working_directory.as_deref(), Some("/srv"));
+        assert_eq!(ShellOptions::default().user_or_root(), "root");
     }
-}
 
-fn wait_service(master: RawFd) -> Result<()> {
-    let mut buf = [0; 8];
-    for _ in 0..30 {
-        if let Ok(n) = read(master, &mut buf) {
-            write(libc::STDOUT_FILENO, &buf[..n])?;
-            return Ok(());
+    #[test]
+    fn c_strings_reject_embedded_nul() {
+        let strs = |last: &str| vec!["LANG=C".to_string(), last.to_string()];
+        assert_eq!(c_strings(&strs("bash")).unwrap().len(), 2);
+        for bad in ["A=1\0", "A\0B=1", "\0"] {
+            assert!(c_strings(&strs(bad)).is_err(), "{:?}", bad);
         }
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(c_string("").is_ok());
     }
-    Err(Errno::ETIMEDOUT)
-}
 
-fn window_resize(master: RawFd) -> Result<()> {
-    unsafe {
-        let mut size: libc::winsize = mem::zeroed();
-        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == -1
-            || libc::ioctl(master, libc::TIOCSWINSZ, &size) == -1
-        {
-            return Err(Errno::last());
+    #[test]
+    fn is_user_or_id_table() {
+        for s in [
+            "root",
+            "_apt",
+            "systemd-network",
+            "user.name",
+            "u1",
+            "0",
+            "1000",
+            "65534",
+        ] {
+            assert!(is_user_or_id(s), "{:?}", s);
+        }
+        let long = "a".repeat(257);
+        for s in [
+            "",
+            "1user",
+            "-user",
+            ".user",
+            "us er",
+            "user:group",
+            "üser",
+            "65535",
+            "4294967295",
+            "4294967296",
+            "-1",
+            long.as_str(),
+        ] {
+            assert!(!is_user_or_id(s), "{:?}", s);
         }
     }
-    Ok(())
-}
 
-fn append_null_ref(str: &str) -> String {
-    let mut str = String::from(str);
-    str.push('\0');
-    str
-}
-
-fn append_null_owned(mut str: String) -> String {
-    str.push('\0');
-    str
+    #[test]
+    fn is_documentation_uri_table() {
+        for uri in [
+            "https://example.org/doc",
+            "http://example.org",
+            "file:/usr/share/doc/angea",
+            "info:bash",
+            "man:systemd(1)",
+        ] {
+            assert!(is_documentation_uri(uri), "{:?}", uri);
+        }
+        for uri in [
+            "",
+            "man:",
+            "https://",
+            "ftp://example.org",
+            "/usr/share/doc",
+            "MAN:bash",
+        ] {
+            assert!(!is_documentation_uri(uri), "{:?}", uri);
+        }
+    }
 }
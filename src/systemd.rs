@@ -1,76 +1,889 @@
+use std::env;
 use std::ffi::CString;
+use std::fs;
+use std::mem;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use nix::dir::Dir;
+use nix::errno::Errno;
 use nix::fcntl::{open, OFlag};
-use nix::mount::{mount, MsFlags};
-use nix::sched::{clone, CloneFlags};
-use nix::sys::signal::{kill, Signal};
-use nix::sys::stat::Mode;
-use nix::unistd::{close, execve, read, Pid};
-use nix::Result;
-
-/// Start a systemd process in a new PID namespace.
-pub fn start() -> Result<()> {
-    let mut stack = [0; 4096];
-    clone(
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{clone, setns, CloneFlags};
+use nix::sys::stat::{stat, Mode, SFlag};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{
+    access, chdir, close, execve, mkdir, pipe2, pivot_root, read, sethostname, write, AccessFlags,
+    Pid,
+};
+
+use crate::capability;
+use crate::error::{Error, Result};
+
+/// Paths masked by `--hardened`
+pub const HARDENED_MASK_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/sys",
+    "/sys/firmware",
+];
+
+/// Locations of the systemd binary across distributions, in probing order
+pub const CANDIDATE_PATHS: &[&str] = &["/lib/systemd/systemd", "/usr/lib/systemd/systemd"];
+
+/// Path of the systemd binary `start` executes, as seen from `rootfs`
+///
+/// `ANGEA_SYSTEMD_PATH` if set, otherwise the first executable of `CANDIDATE_PATHS`.
+///
+/// # Errors
+///
+/// `ENOENT` if no candidate is executable.
+pub fn resolve_path(rootfs: Option<&str>) -> Result<String> {
+    if let Ok(path) = env::var("ANGEA_SYSTEMD_PATH") {
+        return Ok(path);
+    }
+    let root = rootfs.unwrap_or_default().trim_end_matches('/');
+    CANDIDATE_PATHS
+        .iter()
+        .find(|path| is_executable(&format!("{}{}", root, path)))
+        .map(|path| path.to_string())
+        .ok_or(Error::Sys(Errno::ENOENT))
+}
+
+/// Whether `path` is executable by the current user
+pub fn is_executable(path: &str) -> bool {
+    access(path, AccessFlags::X_OK).is_ok()
+}
+
+/// Options for starting systemd
+#[derive(Debug, Default, Clone)]
+pub struct StartOptions {
+    /// Paths hidden from the namespace after the base mounts
+    pub mask_paths: Vec<String>,
+    /// `KEY=VALUE` environment of systemd itself, empty by default
+    pub environment: Vec<String>,
+    /// Root directory to boot from instead of `/`
+    pub rootfs: Option<String>,
+    /// Boot from an overlay with `rootfs` as its read-only lower directory
+    pub overlay: bool,
+    /// Upper directory of the overlay, a throwaway tmpfs when not given
+    pub overlay_upper: Option<String>,
+    /// Work directory of the overlay, on the same filesystem as `overlay_upper`
+    pub overlay_work: Option<String>,
+    /// `--log-level` of systemd, one of `LOG_LEVELS`
+    pub log_level: Option<String>,
+    /// `--log-target` of systemd, one of `LOG_TARGETS`
+    pub log_target: Option<String>,
+    /// Host directories bound into the namespace as `(host, path, read_only)`
+    pub volumes: Vec<(String, String, bool)>,
+    /// `(inside, outside, count)` uid ranges of a new user namespace
+    ///
+    /// With a uid or gid map systemd boots in a new user namespace, so no
+    /// privileges are needed on the host.
+    pub uid_map: Vec<(u32, u32, u32)>,
+    /// `(inside, outside, count)` gid ranges of a new user namespace
+    pub gid_map: Vec<(u32, u32, u32)>,
+    /// Boot in a new network namespace with only loopback up
+    pub private_network: bool,
+    /// Hostname of the booted system, a copy of the host's if unset
+    pub hostname: Option<String>,
+    /// Keep the host cgroup namespace, for kernels without cgroup namespaces
+    pub share_cgroup: bool,
+    /// Keep the host `/sys` instead of mounting sysfs and cgroup2 afresh
+    pub share_sys: bool,
+    /// Size of a fresh tmpfs on `/run`, like `64m`, always mounted with `rootfs`
+    pub run_size: Option<String>,
+    /// Size of a fresh tmpfs on `/tmp`, like `64m`
+    pub tmp_size: Option<String>,
+}
+
+/// Log levels accepted by `systemd --log-level`
+pub const LOG_LEVELS: &[&str] = &[
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// Log targets accepted by `systemd --log-target`
+pub const LOG_TARGETS: &[&str] = &[
+    "console",
+    "console-prefixed",
+    "kmsg",
+    "journal",
+    "journal-or-kmsg",
+    "auto",
+    "null",
+];
+
+/// Stack size of the cloned child, which mounts and formats errors before execve
+const STACK_SIZE: usize = 1024 * 1024;
+
+/// Pid of the systemd `get_running` found last
+const PID_FILE: &str = "/run/angea/systemd.pid";
+
+/// Environment systemd is executed with, nothing but `opts.environment`
+pub(crate) fn init_environ(opts: &StartOptions) -> Result<Vec<CString>> {
+    let environ = opts
+        .environment
+        .iter()
+        .map(|e| CString::new(e.as_str()).map_err(|_| Errno::EINVAL))
+        .collect::<nix::Result<Vec<_>>>()?;
+    Ok(environ)
+}
+
+/// Start a systemd process in a new PID namespace, returning its pid.
+pub fn start(opts: &StartOptions) -> Result<Pid> {
+    let path = resolve_path(opts.rootfs.as_deref()).inspect_err(|_| {
+        eprintln!("systemd not found, set ANGEA_SYSTEMD_PATH to its location");
+    })?;
+    let user_ns = !opts.uid_map.is_empty() || !opts.gid_map.is_empty();
+    if !user_ns {
+        // Namespaces and mounts fail in the child otherwise, with a less clear error
+        capability::require("CAP_SYS_ADMIN")?;
+    }
+    if opts.overlay && opts.rootfs.is_none()
+        || opts.overlay_upper.is_some() != opts.overlay_work.is_some()
+    {
+        return Err(Errno::EINVAL.into());
+    }
+    let mut args = vec![CString::new(path).map_err(|_| Errno::EINVAL)?];
+    if let Some(level) = &opts.log_level {
+        args.push(CString::new(format!("--log-level={}", level)).map_err(|_| Errno::EINVAL)?);
+    }
+    if let Some(target) = &opts.log_target {
+        args.push(CString::new(format!("--log-target={}", target)).map_err(|_| Errno::EINVAL)?);
+    }
+    let environ = init_environ(opts)?;
+    // Always a UTS namespace of its own, systemd sets the hostname from its /etc/hostname
+    let mut flags = CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUTS;
+    if opts.private_network {
+        flags |= CloneFlags::CLONE_NEWNET;
+    }
+    // Rooted at the cgroup of angea, which the fresh cgroup2 mount shows as its top
+    if !opts.share_cgroup {
+        flags |= CloneFlags::CLONE_NEWCGROUP;
+    }
+    // The child waits on this pipe until its id maps are written
+    let sync = if user_ns {
+        flags |= CloneFlags::CLONE_NEWUSER;
+        Some(pipe2(OFlag::O_CLOEXEC)?)
+    } else {
+        None
+    };
+    // Without CLONE_VM the child runs on its own copy, so freeing it on return is safe
+    let mut stack = vec![0; STACK_SIZE];
+    let pid = clone(
         Box::new(|| -> isize {
-            let args = [CString::new("/lib/systemd/systemd").unwrap()];
-            let environ: [CString; 0] = [];
-            mount(
-                Some("proc"),
-                "/proc",
-                Some("proc"),
-                MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC | MsFlags::MS_NODEV,
-                None::<&str>,
-            )
-            .unwrap();
-            execve(args[0].as_c_str(), &args, &environ).unwrap();
-            unreachable!();
+            if let Some((rx, tx)) = sync {
+                let _ = close(tx);
+                let mut byte = [0];
+                if !matches!(read(rx, &mut byte), Ok(1)) {
+                    eprintln!("user namespace was not set up");
+                    return 1;
+                }
+                let _ = close(rx);
+            }
+            // Keep every mount below from propagating back to the host
+            let none = None::<&str>;
+            if let Err(e) = mount(none, "/", none, MsFlags::MS_REC | MsFlags::MS_PRIVATE, none) {
+                eprintln!("failed to make mounts private: {}", e);
+                return 1;
+            }
+            if let Some(rootfs) = &opts.rootfs {
+                let rootfs = if opts.overlay {
+                    let upper = opts.overlay_upper.as_deref();
+                    match mount_overlay(rootfs, upper.zip(opts.overlay_work.as_deref())) {
+                        Ok(merged) => merged,
+                        Err(e) => {
+                            eprintln!("failed to mount overlay on {}: {}", rootfs, e);
+                            return 1;
+                        }
+                    }
+                } else {
+                    rootfs.clone()
+                };
+                if let Err(e) = enter_rootfs(&rootfs, opts.share_sys, &opts.volumes) {
+                    eprintln!("failed to enter rootfs {}: {}", rootfs, e);
+                    return 1;
+                }
+            } else {
+                for (host, path, read_only) in &opts.volumes {
+                    if let Err(e) = bind_volume(host, path, *read_only) {
+                        eprintln!("failed to bind {} on {}: {}", host, path, e);
+                        return 1;
+                    }
+                }
+            }
+            if let Some(hostname) = &opts.hostname {
+                if let Err(e) = sethostname(hostname) {
+                    eprintln!("failed to set hostname {}: {}", hostname, e);
+                    return 1;
+                }
+            }
+            if opts.private_network {
+                if let Err(e) = loopback_up() {
+                    eprintln!("failed to bring up loopback: {}", e);
+                }
+            }
+            if let Err(e) = mount_api_filesystems(opts.share_sys) {
+                eprintln!("failed to mount /proc: {}", e);
+                return 1;
+            }
+            if opts.rootfs.is_some() || opts.run_size.is_some() {
+                if let Err(e) = mount_tmpfs("/run", "mode=755", opts.run_size.as_deref()) {
+                    eprintln!("failed to mount /run: {}", e);
+                    return 1;
+                }
+            }
+            if let Some(size) = &opts.tmp_size {
+                if let Err(e) = mount_tmpfs("/tmp", "mode=1777", Some(size)) {
+                    eprintln!("failed to mount /tmp: {}", e);
+                    return 1;
+                }
+            }
+            for path in &opts.mask_paths {
+                if let Err(e) = mask_path(path) {
+                    eprintln!("failed to mask {}: {}", path, e);
+                }
+            }
+            let e = execve(args[0].as_c_str(), &args, &environ).unwrap_err();
+            eprintln!("failed to execute {:?}: {}", args[0], e);
+            127
         }),
         &mut stack,
-        CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWNS,
+        flags,
         None,
+    );
+    if let Some((rx, tx)) = sync {
+        close(rx)?;
+        // Closing tx without writing makes the child give up
+        let ret = pid
+            .map_err(Error::from)
+            .and_then(|pid| write_id_maps(pid, opts))
+            .and_then(|()| Ok(write(tx, &[0])?));
+        close(tx)?;
+        ret?;
+    }
+    let pid = pid?;
+    remember(Path::new(PID_FILE), pid);
+    Ok(pid)
+}
+
+/// Write the uid and gid maps of `pid`, which is in a new user namespace
+fn write_id_maps(pid: Pid, opts: &StartOptions) -> Result<()> {
+    // The kernel takes each map in a single write
+    let format = |map: &[(u32, u32, u32)]| {
+        map.iter()
+            .map(|(inside, outside, count)| format!("{} {} {}\n", inside, outside, count))
+            .collect::<String>()
+    };
+    if !opts.gid_map.is_empty() {
+        // Without CAP_SETGID on the host, a gid map is only taken with setgroups denied
+        if capability::require("CAP_SETGID").is_err() {
+            fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+        }
+        fs::write(format!("/proc/{}/gid_map", pid), format(&opts.gid_map))?;
+    }
+    if !opts.uid_map.is_empty() {
+        fs::write(format!("/proc/{}/uid_map", pid), format(&opts.uid_map))?;
+    }
+    Ok(())
+}
+
+/// Set the loopback interface of the current network namespace up
+fn loopback_up() -> Result<()> {
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if sock == -1 {
+        return Err(Errno::last().into());
+    }
+    let ret = unsafe {
+        let mut req: libc::ifreq = mem::zeroed();
+        for (dst, src) in req.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        if libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req) == -1 {
+            Err(Errno::last())
+        } else {
+            req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            Errno::result(libc::ioctl(sock, libc::SIOCSIFFLAGS, &req)).map(drop)
+        }
+    };
+    close(sock)?;
+    Ok(ret?)
+}
+
+/// Mount `/proc`, and unless `share_sys`, sysfs on `/sys` with the cgroup2 hierarchy
+///
+/// Only `/proc` is required, the `/sys` mounts are best effort.
+fn mount_api_filesystems(share_sys: bool) -> Result<()> {
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC | MsFlags::MS_NODEV;
+    mount(Some("proc"), "/proc", Some("proc"), flags, None::<&str>)?;
+    if share_sys {
+        return Ok(());
+    }
+    for (fstype, target) in [("sysfs", "/sys"), ("cgroup2", "/sys/fs/cgroup")] {
+        if let Err(e) = mount(Some(fstype), target, Some(fstype), flags, None::<&str>) {
+            eprintln!("failed to mount {}: {}", target, e);
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Mount a tmpfs on `target`, limited to `size` if given
+fn mount_tmpfs(target: &str, mode: &str, size: Option<&str>) -> Result<()> {
+    let data = match size {
+        Some(size) => format!("{},size={}", mode, size),
+        None => mode.to_string(),
+    };
+    mount(
+        Some("tmpfs"),
+        target,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(data.as_str()),
     )?;
     Ok(())
 }
 
+/// Bind `host` on `path`, read-only with `read_only`
+fn bind_volume(host: &str, path: &str, read_only: bool) -> Result<()> {
+    let none = None::<&str>;
+    mount(
+        Some(host),
+        path,
+        none,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        none,
+    )?;
+    if read_only {
+        // MS_RDONLY is ignored on the initial bind, it only applies on a remount
+        mount(
+            none,
+            path,
+            none,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            none,
+        )?;
+    }
+    Ok(())
+}
+
+/// Mount an overlay with `rootfs` as the lower directory, returning the merged directory
+///
+/// With `dirs` given as `(upper, work)` the overlay is stacked on `rootfs` itself.
+/// Otherwise a tmpfs covers `rootfs` and holds the upper, work and merged
+/// directories, so every change is gone with the mount namespace.
+fn mount_overlay(rootfs: &str, dirs: Option<(&str, &str)>) -> Result<String> {
+    let root = rootfs.trim_end_matches('/');
+    // `rootfs` may get covered below, reach the lower directory through a fd
+    let lower = open(
+        rootfs,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let ret = (|| {
+        let (upper, work, merged) = match dirs {
+            Some((upper, work)) => (upper.to_string(), work.to_string(), root.to_string()),
+            None => {
+                mount_tmpfs(rootfs, "mode=755", None)?;
+                for dir in ["upper", "work", "merged"] {
+                    mkdir(
+                        format!("{}/{}", root, dir).as_str(),
+                        Mode::from_bits_truncate(0o755),
+                    )?;
+                }
+                (
+                    format!("{}/upper", root),
+                    format!("{}/work", root),
+                    format!("{}/merged", root),
+                )
+            }
+        };
+        // Separators in the paths would be taken as more mount options
+        if [&upper, &work].iter().any(|p| p.contains([',', ':'])) {
+            return Err(Errno::EINVAL.into());
+        }
+        let data = format!(
+            "lowerdir=/proc/self/fd/{},upperdir={},workdir={}",
+            lower, upper, work
+        );
+        mount(
+            Some("overlay"),
+            merged.as_str(),
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(data.as_str()),
+        )?;
+        Ok(merged)
+    })();
+    close(lower)?;
+    ret
+}
+
+/// Make `rootfs` the root of the mount namespace
+///
+/// Host `/dev` is bound into it, and host `/sys` too with `share_sys`,
+/// followed by `volumes` relative to `rootfs`.
+/// The old root is detached so no host path stays reachable.
+/// Mounts must already be private, as pivot_root refuses shared ones.
+fn enter_rootfs(rootfs: &str, share_sys: bool, volumes: &[(String, String, bool)]) -> Result<()> {
+    let none = None::<&str>;
+    // pivot_root needs the new root to be a mount point
+    mount(
+        Some(rootfs),
+        rootfs,
+        none,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        none,
+    )?;
+    let binds: &[&str] = if share_sys {
+        &["/dev", "/sys"]
+    } else {
+        &["/dev"]
+    };
+    for path in binds {
+        let target = format!("{}{}", rootfs.trim_end_matches('/'), path);
+        mount(
+            Some(*path),
+            target.as_str(),
+            none,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            none,
+        )?;
+    }
+
+    // Host paths are unreachable once the old root is detached
+    for (host, path, read_only) in volumes {
+        let target = format!("{}{}", rootfs.trim_end_matches('/'), path);
+        bind_volume(host, &target, *read_only)?;
+    }
+
+    // Stack the new root over the old one, then detach the old one
+    chdir(rootfs)?;
+    pivot_root(".", ".")?;
+    umount2(".", MntFlags::MNT_DETACH)?;
+    chdir("/")?;
+    Ok(())
+}
+
+/// Hide a path by mounting `/dev/null` over files or an empty read-only tmpfs over directories.
+///
+/// Missing paths are skipped.
+fn mask_path(path: &str) -> Result<()> {
+    let st = match stat(path) {
+        Ok(st) => st,
+        Err(Errno::ENOENT) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR {
+        mount(
+            Some("tmpfs"),
+            path,
+            Some("tmpfs"),
+            MsFlags::MS_RDONLY,
+            None::<&str>,
+        )?;
+    } else {
+        mount(
+            Some("/dev/null"),
+            path,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )?;
+    }
+    Ok(())
+}
+
 /// Try to get running systemd pid from procfs
+///
+/// Matches on the basename of argv[0], as `comm` is truncated and may be renamed.
+/// Only a systemd in another PID namespace counts, never the init of our own one.
+/// The pid found last is checked first, all of `/proc` is only scanned if that fails.
 pub fn get_running() -> Result<Option<Pid>> {
-    let proc = Dir::open("/proc", OFlag::O_DIRECTORY, Mode::empty())?;
-    for entry in proc {
+    get_running_in(Path::new("/proc"), Path::new(PID_FILE))
+}
+
+/// `get_running` on the procfs at `proc`, remembering the pid in `pid_file`
+pub(crate) fn get_running_in(proc: &Path, pid_file: &Path) -> Result<Option<Pid>> {
+    let own_ns = fs::read_link(proc.join("self/ns/pid"))
+        .map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO)))?;
+    let last = fs::read_to_string(pid_file)
+        .ok()
+        .and_then(|s| s.trim().parse().ok());
+    if let Some(pid) = last.filter(|&pid| is_foreign_systemd(proc, pid, &own_ns)) {
+        return Ok(Some(Pid::from_raw(pid)));
+    }
+    let dir = Dir::open(proc, OFlag::O_DIRECTORY, Mode::empty())?;
+    for entry in dir {
         match entry {
             Ok(e) => {
                 let file_name = e.file_name().to_string_lossy();
                 if file_name == "." || file_name == ".." {
                     continue;
                 }
-                let pid = match file_name.parse() {
+                let pid: i32 = match file_name.parse() {
                     Ok(p) => p,
                     Err(_) => continue,
                 };
-                let mut path = String::from("/proc/");
-                path.push_str(&file_name);
-                path.push_str("/comm");
-
-                let fd = open(path.as_str(), OFlag::O_RDONLY, Mode::empty())?;
-                let mut buf = [0; 8];
-                let n = read(fd, &mut buf)?;
-                if &buf[..n] == b"systemd\n" {
+                if is_foreign_systemd(proc, pid, &own_ns) {
+                    remember(pid_file, Pid::from_raw(pid));
                     return Ok(Some(Pid::from_raw(pid)));
                 }
-                close(fd)?;
             }
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         }
     }
     Ok(None)
 }
 
-/// Kill running process
-pub fn shutdown() -> Result<()> {
-    if let Some(pid) = get_running()? {
-        kill(pid, Signal::SIGKILL)
-    } else {
-        Ok(())
+/// Whether `pid` is a systemd in a PID namespace other than `own_ns`
+fn is_foreign_systemd(proc: &Path, pid: i32, own_ns: &Path) -> bool {
+    let dir = proc.join(pid.to_string());
+    // Processes may exit while scanning
+    let cmdline = match fs::read(dir.join("cmdline")) {
+        Ok(cmdline) => cmdline,
+        Err(_) => return false,
+    };
+    let ns = fs::read_link(dir.join("ns/pid"));
+    is_systemd(&cmdline) && ns.is_ok_and(|ns| ns != own_ns)
+}
+
+/// Save `pid` to `pid_file` for the fast path of `get_running`, best effort
+fn remember(pid_file: &Path, pid: Pid) {
+    let _ = pid_file
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(pid_file, pid.to_string()));
+}
+
+/// Whether the NUL separated `cmdline` runs a binary named `systemd`
+fn is_systemd(cmdline: &[u8]) -> bool {
+    let argv0 = cmdline.split(|&b| b == 0).next().unwrap_or_default();
+    argv0.rsplit(|&b| b == b'/').next() == Some(b"systemd")
+}
+
+/// Signals `shutdown` sends in turn until systemd exits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownMethod {
+    /// SIGRTMIN+3 (systemd poweroff), then SIGKILL
+    #[default]
+    Systemd,
+    /// SIGTERM, then SIGRTMIN+3, then SIGKILL, for inits that stop on SIGTERM
+    Terminate,
+}
+
+/// Default time systemd gets to power off before it is killed
+pub const POWEROFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default wait for exit after SIGTERM, and after SIGKILL
+pub const SIGNAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `shutdown` waits for systemd to exit after each signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownTimeouts {
+    /// Wait after SIGRTMIN+3, which runs the stop jobs of all units
+    pub poweroff: Duration,
+    /// Wait after SIGTERM, only sent by `ShutdownMethod::Terminate`
+    pub term: Duration,
+    /// Wait after SIGKILL
+    pub kill: Duration,
+}
+
+impl Default for ShutdownTimeouts {
+    fn default() -> Self {
+        ShutdownTimeouts {
+            poweroff: POWEROFF_TIMEOUT,
+            term: SIGNAL_TIMEOUT,
+            kill: SIGNAL_TIMEOUT,
+        }
+    }
+}
+
+impl ShutdownMethod {
+    /// Signals with how long to wait for exit after each
+    fn steps(self, timeouts: &ShutdownTimeouts) -> Vec<(c_int, Duration)> {
+        let poweroff = (libc::SIGRTMIN() + 3, timeouts.poweroff);
+        let kill = (libc::SIGKILL, timeouts.kill);
+        match self {
+            ShutdownMethod::Systemd => vec![poweroff, kill],
+            ShutdownMethod::Terminate => vec![(libc::SIGTERM, timeouts.term), poweroff, kill],
+        }
+    }
+}
+
+impl FromStr for ShutdownMethod {
+    type Err = Errno;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Errno> {
+        match s {
+            "systemd" => Ok(ShutdownMethod::Systemd),
+            "sigterm" => Ok(ShutdownMethod::Terminate),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+/// How `shutdown` ended systemd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// No systemd was running
+    NotRunning,
+    /// systemd exited on its own after a shutdown request
+    Exited,
+    /// systemd had to be killed with SIGKILL
+    Killed,
+}
+
+/// Stop running process, escalating through the signals of `method`
+///
+/// systemd gets `timeouts.poweroff` to power off cleanly before it is killed.
+pub fn shutdown(method: ShutdownMethod, timeouts: &ShutdownTimeouts) -> Result<ShutdownOutcome> {
+    match get_running()? {
+        Some(pid) => stop(pid, method, timeouts),
+        None => Ok(ShutdownOutcome::NotRunning),
+    }
+}
+
+/// Stop systemd `pid`, escalating through the signals of `method`
+///
+/// # Errors
+///
+/// `Error::Timeout` if `pid` survives even SIGKILL.
+pub fn stop(
+    pid: Pid,
+    method: ShutdownMethod,
+    timeouts: &ShutdownTimeouts,
+) -> Result<ShutdownOutcome> {
+    for (signal, timeout) in method.steps(timeouts) {
+        let outcome = if signal == libc::SIGKILL {
+            ShutdownOutcome::Killed
+        } else {
+            ShutdownOutcome::Exited
+        };
+        if unsafe { libc::kill(pid.as_raw(), signal) } == -1 {
+            return match Errno::last() {
+                Errno::ESRCH => Ok(outcome),
+                e => Err(e.into()),
+            };
+        }
+        if wait_exit(pid, timeout) {
+            return Ok(outcome);
+        }
+    }
+    Err(Error::Timeout("systemd to exit"))
+}
+
+/// Wait up to `timeout` for `pid` to exit, return whether it did
+fn wait_exit(pid: Pid, timeout: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        if !is_alive(pid) {
+            reap(pid);
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Switch this process into the mount namespace of `pid`
+///
+/// Paths then resolve as `pid` sees them, though `/proc` shows the PID namespace
+/// of `pid`, where angea itself is not visible.
+pub fn enter_mount_ns(pid: Pid) -> Result<()> {
+    let path = format!("/proc/{}/ns/mnt", pid);
+    let fd = open(
+        path.as_str(),
+        OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let ret = setns(fd, CloneFlags::CLONE_NEWNS);
+    close(fd)?;
+    Ok(ret?)
+}
+
+/// How long `pid` has been running
+pub fn uptime(pid: Pid) -> Result<Duration> {
+    let read = |path: String| fs::read_to_string(path).map_err(|_| Errno::ESRCH);
+    let stat = read(format!("/proc/{}/stat", pid))?;
+    let uptime = read(String::from("/proc/uptime"))?;
+    // Start time is the 22nd field, the 20th after the parenthesized comm
+    let started: u64 = stat
+        .rsplit_once(')')
+        .and_then(|(_, rest)| rest.split_whitespace().nth(19))
+        .and_then(|s| s.parse().ok())
+        .ok_or(Errno::EINVAL)?;
+    let uptime: f64 = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or(Errno::EINVAL)?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    Ok(Duration::from_secs_f64(
+        (uptime - started as f64 / ticks).max(0.0),
+    ))
+}
+
+/// Whether `pid` exists and is not a zombie
+pub fn is_alive(pid: Pid) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // State follows the parenthesized comm, which may contain spaces
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, rest)| !rest.trim_start().starts_with('Z')),
+        Err(_) => false,
+    }
+}
+
+/// Reap `pid` if it is an exited child of this process, returning how it ended
+///
+/// The init started by `start` is such a child, without it it stays a zombie
+/// as long as angea runs. Returns `None` for a live pid or one angea did not start.
+pub fn reap(pid: Pid) -> Option<WaitStatus> {
+    // __WALL as the init is cloned without an exit signal
+    match waitpid(pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+        Ok(WaitStatus::StillAlive) | Err(_) => None,
+        Ok(status) => Some(status),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process;
+
+    use crate::bus::tests::open_fds;
+
+    /// Fake procfs named `name`, angea itself in PID namespace 1
+    pub(crate) fn fixture(name: &str) -> PathBuf {
+        let proc = env::temp_dir().join(format!("angea-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&proc);
+        fs::create_dir_all(proc.join("self/ns")).unwrap();
+        symlink("pid:[1]", proc.join("self/ns/pid")).unwrap();
+        proc
+    }
+
+    pub(crate) fn add_process(proc: &Path, pid: i32, cmdline: &[u8], ns: u32) {
+        let dir = proc.join(pid.to_string());
+        fs::create_dir_all(dir.join("ns")).unwrap();
+        fs::write(dir.join("cmdline"), cmdline).unwrap();
+        symlink(format!("pid:[{}]", ns), dir.join("ns/pid")).unwrap();
+    }
+
+    #[test]
+    fn shutdown_steps_take_timeouts() {
+        let timeouts = ShutdownTimeouts {
+            poweroff: Duration::from_secs(30),
+            term: Duration::from_secs(2),
+            kill: Duration::from_secs(1),
+        };
+        let poweroff = libc::SIGRTMIN() + 3;
+        assert_eq!(
+            ShutdownMethod::Systemd.steps(&timeouts),
+            [
+                (poweroff, timeouts.poweroff),
+                (libc::SIGKILL, timeouts.kill)
+            ]
+        );
+        assert_eq!(
+            ShutdownMethod::Terminate.steps(&timeouts),
+            [
+                (libc::SIGTERM, timeouts.term),
+                (poweroff, timeouts.poweroff),
+                (libc::SIGKILL, timeouts.kill)
+            ]
+        );
+        let defaults = ShutdownMethod::Terminate.steps(&ShutdownTimeouts::default());
+        assert_eq!(defaults[0].1, Duration::from_secs(5));
+        assert_eq!(defaults[2].1, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn is_systemd_matches_basename_of_argv0() {
+        assert!(is_systemd(b"/usr/lib/systemd/systemd\0--system\0"));
+        assert!(is_systemd(b"systemd\0"));
+        assert!(!is_systemd(b"/usr/lib/systemd/systemd-journald\0"));
+        assert!(!is_systemd(b"/sbin/init\0systemd\0"));
+        assert!(!is_systemd(b""));
+    }
+
+    #[test]
+    fn get_running_finds_systemd_of_other_namespace() {
+        let proc = fixture("proc-scan");
+        let pid_file = proc.join("run/systemd.pid");
+        add_process(&proc, 10, b"/sbin/init\0", 2);
+        add_process(&proc, 20, b"/usr/lib/systemd/systemd\0--user\0", 1);
+        add_process(&proc, 30, b"/lib/systemd/systemd-journald\0", 2);
+        add_process(&proc, 40, b"/lib/systemd/systemd\0", 2);
+        fs::create_dir(proc.join("sys")).unwrap();
+
+        assert_eq!(
+            get_running_in(&proc, &pid_file).unwrap(),
+            Some(Pid::from_raw(40))
+        );
+        assert_eq!(fs::read_to_string(&pid_file).unwrap(), "40");
+        fs::remove_dir_all(&proc).unwrap();
+    }
+
+    #[test]
+    fn get_running_prefers_remembered_pid() {
+        let proc = fixture("proc-remembered");
+        let pid_file = proc.join("systemd.pid");
+        add_process(&proc, 40, b"/lib/systemd/systemd\0", 2);
+        add_process(&proc, 50, b"/lib/systemd/systemd\0", 3);
+        fs::write(&pid_file, "50\n").unwrap();
+        assert_eq!(
+            get_running_in(&proc, &pid_file).unwrap(),
+            Some(Pid::from_raw(50))
+        );
+
+        // A stale pid falls back to the scan
+        fs::remove_dir_all(proc.join("50")).unwrap();
+        assert_eq!(
+            get_running_in(&proc, &pid_file).unwrap(),
+            Some(Pid::from_raw(40))
+        );
+        assert_eq!(fs::read_to_string(&pid_file).unwrap(), "40");
+        fs::remove_dir_all(&proc).unwrap();
+    }
+
+    #[test]
+    fn get_running_without_systemd_is_none() {
+        let proc = fixture("proc-none");
+        let pid_file = proc.join("systemd.pid");
+        add_process(&proc, 1, b"/usr/lib/systemd/systemd\0", 1);
+        add_process(&proc, 10, b"bash\0", 2);
+        assert_eq!(get_running_in(&proc, &pid_file).unwrap(), None);
+        assert!(!pid_file.exists());
+        fs::remove_dir_all(&proc).unwrap();
+    }
+
+    #[test]
+    fn get_running_closes_procfs() {
+        let proc = fixture("scan-fds");
+        for pid in 2..10 {
+            add_process(&proc, pid, b"/bin/sh\0", 1);
+        }
+        let pid_file = proc.join("systemd.pid");
+        let before = open_fds();
+        for _ in 0..5000 {
+            assert_eq!(get_running_in(&proc, &pid_file).unwrap(), None);
+        }
+        // Other tests open fds meanwhile, a leak would show up as thousands
+        assert!(open_fds() < before + 100);
+        fs::remove_dir_all(proc).unwrap();
     }
 }